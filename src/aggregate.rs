@@ -0,0 +1,451 @@
+//! Merging the products of all receipts into one entry per product name.
+
+use crate::parse::split_aliases;
+use crate::{clean_product_name, Product};
+use anyhow::Result;
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use serde::ser::{Error as _, Serialize, Serializer};
+use serde_json::value::RawValue;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Similarity between two product names on a 0–100 scale
+pub trait NameMatcher {
+    fn score(&self, existing: &str, candidate: &str) -> i64;
+}
+
+/// Skim subsequence matching, the default
+#[derive(Default)]
+pub struct SkimNameMatcher(SkimMatcherV2);
+
+impl NameMatcher for SkimNameMatcher {
+    fn score(&self, existing: &str, candidate: &str) -> i64 {
+        // Raw skim scores grow with the length of the match, so long names always cleared the
+        // threshold and short ones rarely did. Scale by the candidate's score against itself,
+        // the best it can do, so the result is a 0–100 share like the other matchers.
+        let best = self.0.fuzzy_match(candidate, candidate).unwrap_or(0);
+        if best <= 0 {
+            return 0;
+        }
+        let score = self.0.fuzzy_match(existing, candidate).unwrap_or(0);
+        (score * 100 / best).clamp(0, 100)
+    }
+}
+
+/// Jaro-Winkler similarity, tends to work best for short product names
+pub struct JaroNameMatcher;
+
+impl NameMatcher for JaroNameMatcher {
+    fn score(&self, existing: &str, candidate: &str) -> i64 {
+        (strsim::jaro_winkler(existing, candidate) * 100.0).round() as i64
+    }
+}
+
+/// Normalized Levenshtein ratio
+pub struct LevenshteinNameMatcher;
+
+impl NameMatcher for LevenshteinNameMatcher {
+    fn score(&self, existing: &str, candidate: &str) -> i64 {
+        (strsim::normalized_levenshtein(existing, candidate) * 100.0).round() as i64
+    }
+}
+
+/// Normalized Levenshtein ratio of the names' words in alphabetical order, so reordered names
+/// ("cola coca", "coca cola") score like identical ones. Repeated words count once
+pub struct TokenSetNameMatcher;
+
+impl NameMatcher for TokenSetNameMatcher {
+    fn score(&self, existing: &str, candidate: &str) -> i64 {
+        let sorted_words = |name: &str| {
+            let name = clean_product_name(name);
+            let words: BTreeSet<&str> = name.split_whitespace().collect();
+            words.into_iter().collect::<Vec<_>>().join(" ")
+        };
+        (strsim::normalized_levenshtein(&sorted_words(existing), &sorted_words(candidate)) * 100.0).round() as i64
+    }
+}
+
+/// Currency assumed for lines without a detected currency
+pub const DEFAULT_CURRENCY: &str = "EUR";
+
+/// Names scoring above this are merged unless configured otherwise
+pub const DEFAULT_FUZZY_THRESHOLD: i64 = 80;
+
+/// Controls how [`aggregate_products`] merges similar names
+#[derive(Clone, Copy, Debug)]
+pub struct AggregateOptions {
+    /// Names scoring strictly above this (0–100) are merged. Below about 60 unrelated products
+    /// start to merge; at 100 only names the matcher considers identical do.
+    pub threshold: i64,
+    /// Compare each product against at most the N most recently added names
+    pub max_compare: Option<usize>,
+}
+
+impl Default for AggregateOptions {
+    fn default() -> Self {
+        Self { threshold: DEFAULT_FUZZY_THRESHOLD, max_compare: None }
+    }
+}
+
+/// A product name with the prices of all items merged into it
+#[derive(Debug)]
+pub struct AggregatedProduct {
+    pub name: String,
+    /// ISO code; products are only merged within the same currency
+    pub currency: &'static str,
+    pub total: f64,
+    /// Total excluding VAT, `None` if any merged item has no known VAT class
+    pub net_total: Option<f64>,
+    /// Units merged into this entry, summed over the items' quantities
+    pub count: u32,
+    /// Items merged into this entry, however many units each covers: "2x Cola" and "Cola" are
+    /// two appearances and three units
+    pub appearances: u32,
+    /// Lowest and highest unit price of the merged items
+    pub min_unit_price: f64,
+    pub max_unit_price: f64,
+}
+
+impl AggregatedProduct {
+    /// An entry named `name` holding `quantity` units of `product`
+    fn new(name: String, currency: &'static str, product: &Product, quantity: u32) -> Self {
+        let unit_price = product.price / f64::from(quantity.max(1));
+        AggregatedProduct {
+            name,
+            currency,
+            total: product.price,
+            net_total: net_price(product.price, product.vat_class),
+            count: quantity,
+            appearances: 1,
+            min_unit_price: unit_price,
+            max_unit_price: unit_price,
+        }
+    }
+
+    /// Merges `quantity` units of `product` into this entry
+    fn add(&mut self, product: &Product, quantity: u32) {
+        let unit_price = product.price / f64::from(quantity.max(1));
+        self.total += product.price;
+        self.net_total = self.net_total.zip(net_price(product.price, product.vat_class)).map(|(a, b)| a + b);
+        self.count += quantity;
+        self.appearances += 1;
+        self.min_unit_price = self.min_unit_price.min(unit_price);
+        self.max_unit_price = self.max_unit_price.max(unit_price);
+    }
+
+    /// Average price of one unit, `None` without any units
+    pub fn unit_price(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.total / f64::from(self.count))
+    }
+}
+
+/// Merges products with similar names in the same currency, sorted by total descending. Names
+/// are compared as [`clean_product_name`] leaves them, so "Milch" and "milch" merge; the entry is
+/// named after the first item
+pub fn aggregate_products(
+    products: Vec<Product>,
+    matcher: &dyn NameMatcher,
+    options: &AggregateOptions,
+) -> Vec<AggregatedProduct> {
+    // Keyed by matching name and currency: summing "$" and "€" prices of the same product is meaningless
+    let mut aggregated: HashMap<(String, &'static str), AggregatedProduct> = HashMap::new();
+    // Keys in insertion order so --max-compare can pick the most recent ones
+    let mut insertion_order: Vec<(String, &'static str)> = Vec::new();
+
+    for product in products {
+        let currency = product.currency.unwrap_or(DEFAULT_CURRENCY);
+        let match_name = clean_product_name(&product.name);
+        let mut found_match = false;
+        let mut best_match_key = (String::new(), currency);
+        let mut best_score = 0;
+
+        // An identical name always merges, whatever the threshold and --max-compare
+        let exact_key = (match_name.clone(), currency);
+        if aggregated.contains_key(&exact_key) {
+            best_match_key = exact_key;
+            found_match = true;
+        }
+
+        // Otherwise try to find existing similar product name in the same currency
+        let candidates = insertion_order
+            .iter()
+            .rev()
+            .filter(|(_, existing_currency)| *existing_currency == currency)
+            .take(if found_match { 0 } else { options.max_compare.unwrap_or(usize::MAX) });
+        for existing_key in candidates {
+            let score = matcher.score(&existing_key.0, &match_name);
+            if score > options.threshold && score > best_score {
+                best_score = score;
+                best_match_key = existing_key.clone();
+                found_match = true;
+            }
+        }
+
+        if found_match {
+            aggregated.get_mut(&best_match_key).unwrap().add(&product, product.quantity);
+        } else {
+            let key = (match_name, currency);
+            insertion_order.push(key.clone());
+            let entry = AggregatedProduct::new(product.name.clone(), currency, &product, product.quantity);
+            aggregated.insert(key, entry);
+        }
+    }
+
+    sorted_by_total(aggregated.into_values().collect())
+}
+
+/// Merges only items with the same name, unit price and currency, counting the units instead of
+/// folding similar names together. Two scans of the same article on one receipt stay visible as
+/// "apfel" with a count of 2, while "apfel" at a different price keeps its own entry.
+pub fn count_identical_items(products: Vec<Product>) -> Vec<AggregatedProduct> {
+    // Unit price in cents, so float noise from dividing line totals doesn't split entries
+    let mut counted: HashMap<(String, i64, &'static str), AggregatedProduct> = HashMap::new();
+
+    for product in products {
+        let currency = product.currency.unwrap_or(DEFAULT_CURRENCY);
+        let quantity = product.quantity.max(1);
+        let unit_cents = (product.price / f64::from(quantity) * 100.0).round() as i64;
+        counted
+            .entry((clean_product_name(&product.name), unit_cents, currency))
+            .and_modify(|existing| existing.add(&product, quantity))
+            .or_insert_with(|| AggregatedProduct::new(product.name.clone(), currency, &product, quantity));
+    }
+
+    sorted_by_total(counted.into_values().collect())
+}
+
+/// Sorts by total descending, then by name so equal totals keep a stable order across runs
+fn sorted_by_total(mut products: Vec<AggregatedProduct>) -> Vec<AggregatedProduct> {
+    products.sort_by(|a, b| {
+        b.total.total_cmp(&a.total)
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.currency.cmp(b.currency))
+    });
+    products
+}
+
+/// Category of products that no rule of the categories file matches
+pub const UNCATEGORIZED: &str = "uncategorized";
+
+/// Keyword rules that put products into budget categories such as "groceries" or "alcohol"
+#[derive(Clone, Debug, Default)]
+pub struct Categories {
+    /// Cleaned keywords and their category, in file order
+    rules: Vec<(String, String)>,
+}
+
+impl Categories {
+    /// Reads a categories file: one "category = keyword1, keyword2" rule per line, blank lines and
+    /// lines starting with "#" are ignored. Keywords are cleaned like product names and matched
+    /// as substrings, not regexes. A keyword listed for two categories in the same file is an error.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut rules: Vec<(String, String)> = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((category, keywords)) = line.split_once('=') else {
+                anyhow::bail!("line {}: expected \"category = keyword1, keyword2\", got \"{}\"", index + 1, line);
+            };
+            let category = category.trim();
+            if category.is_empty() {
+                anyhow::bail!("line {}: the category is empty", index + 1);
+            }
+
+            for keyword in split_aliases(keywords).map(clean_product_name).filter(|keyword| !keyword.is_empty()) {
+                match rules.iter().find(|(existing, _)| *existing == keyword) {
+                    Some((_, existing)) if existing != category => anyhow::bail!(
+                        "line {}: \"{}\" is already a keyword of \"{}\"",
+                        index + 1,
+                        keyword,
+                        existing
+                    ),
+                    Some(_) => {}
+                    None => rules.push((keyword, category.to_string())),
+                }
+            }
+        }
+        Ok(Categories { rules })
+    }
+
+    /// Adds the rules of a later file. They are checked before the existing ones, and a keyword
+    /// both list belongs to `other`'s category
+    pub fn merge(&mut self, other: Categories) {
+        self.rules.retain(|(keyword, _)| !other.rules.iter().any(|(other_keyword, _)| other_keyword == keyword));
+        self.rules.splice(0..0, other.rules);
+    }
+
+    /// The category of the first rule with a keyword contained in the cleaned `name`, so
+    /// "bier" matches "pils bier 0,5l" and "bierschinken" alike. Case doesn't matter
+    pub fn category(&self, name: &str) -> Option<&str> {
+        let name = clean_product_name(name);
+        self.rules
+            .iter()
+            .find(|(keyword, _)| name.contains(keyword.as_str()))
+            .map(|(_, category)| category.as_str())
+    }
+}
+
+/// Everything spent in one category
+#[derive(Debug)]
+pub struct CategoryTotal {
+    /// As written in the categories file, or [`UNCATEGORIZED`]
+    pub name: String,
+    /// ISO code; like products, categories are only summed within the same currency
+    pub currency: &'static str,
+    pub total: f64,
+    /// Units in this category, summed over the items' quantities
+    pub count: u32,
+}
+
+/// Sums `products` per category and currency, sorted by total descending with the
+/// [`UNCATEGORIZED`] products last
+pub fn category_totals(products: &[Product], categories: &Categories) -> Vec<CategoryTotal> {
+    let mut totals: HashMap<(&str, &'static str), CategoryTotal> = HashMap::new();
+    for product in products {
+        let name = categories.category(&product.name).unwrap_or(UNCATEGORIZED);
+        let currency = product.currency.unwrap_or(DEFAULT_CURRENCY);
+        let entry = totals.entry((name, currency)).or_insert_with(|| CategoryTotal {
+            name: name.to_string(),
+            currency,
+            total: 0.0,
+            count: 0,
+        });
+        entry.total += product.price;
+        entry.count += product.quantity;
+    }
+
+    let mut totals: Vec<CategoryTotal> = totals.into_values().collect();
+    totals.sort_by(|a, b| {
+        (a.name == UNCATEGORIZED).cmp(&(b.name == UNCATEGORIZED))
+            .then_with(|| b.total.total_cmp(&a.total))
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.currency.cmp(b.currency))
+    });
+    totals
+}
+
+/// Summary numbers for a run, see `stats`
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    /// Entries left after aggregation
+    pub unique_products: usize,
+    /// Items parsed from the receipts, before aggregation
+    pub line_items: usize,
+    /// Mean and median item price, `None` without items
+    pub mean_price: Option<f64>,
+    pub median_price: Option<f64>,
+    /// Name and price of the most expensive single item
+    pub most_expensive: Option<(String, f64)>,
+    /// Items per currency printed on their line
+    pub detected_currencies: BTreeMap<&'static str, usize>,
+    /// Items without a currency on their line, counted in [`DEFAULT_CURRENCY`]
+    pub assumed_currency: usize,
+}
+
+/// Counts and price distribution of the parsed `products` and their `aggregated` form. The
+/// median of an even number of items is the mean of the two middle prices.
+pub fn stats(aggregated: &[AggregatedProduct], products: &[Product]) -> Stats {
+    let mut prices: Vec<f64> = products.iter().map(|product| product.price).collect();
+    prices.sort_by(f64::total_cmp);
+
+    let middle = prices.len() / 2;
+    let median_price = match prices.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((prices[middle - 1] + prices[middle]) / 2.0),
+        _ => Some(prices[middle]),
+    };
+    let mean_price = (!prices.is_empty()).then(|| prices.iter().sum::<f64>() / prices.len() as f64);
+    let most_expensive = products
+        .iter()
+        .max_by(|a, b| a.price.total_cmp(&b.price))
+        .map(|product| (product.name.clone(), product.price));
+    let mut detected_currencies: BTreeMap<&'static str, usize> = BTreeMap::new();
+    for currency in products.iter().filter_map(|product| product.currency) {
+        *detected_currencies.entry(currency).or_default() += 1;
+    }
+
+    Stats {
+        unique_products: aggregated.len(),
+        line_items: products.len(),
+        mean_price,
+        median_price,
+        most_expensive,
+        assumed_currency: products.len() - detected_currencies.values().sum::<usize>(),
+        detected_currencies,
+    }
+}
+
+/// Price excluding VAT, if the item's VAT class is known
+fn net_price(gross: f64, vat_class: Option<char>) -> Option<f64> {
+    let rate = vat_class.and_then(vat_rate)?;
+    Some(gross / (1.0 + rate as f64 / 100.0))
+}
+
+/// VAT rate in percent for a German receipt tax class
+pub fn vat_rate(class: char) -> Option<u32> {
+    match class {
+        'A' => Some(19),
+        'B' => Some(7),
+        _ => None,
+    }
+}
+
+/// How amounts are rounded to cents for display
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rounding {
+    /// Round half away from zero: 0,125 -> 0,13
+    #[default]
+    HalfUp,
+    /// Banker's rounding, round half to even: 0,125 -> 0,12
+    HalfEven,
+    /// Drop fractional cents: 0,129 -> 0,12
+    Truncate,
+}
+
+/// `value` rounded to cents as `rounding` asks
+pub fn round_price(value: f64, rounding: Rounding) -> f64 {
+    // Snap away binary noise first so 2.675 * 100.0 = 267.49999999999997 counts as a tie
+    let cents = (value * 100.0 * 1e6).round() / 1e6;
+    let rounded = match rounding {
+        Rounding::HalfUp => cents.round(),
+        Rounding::HalfEven => cents.round_ties_even(),
+        Rounding::Truncate => cents.trunc(),
+    };
+    rounded / 100.0
+}
+
+/// Serializes an amount with exactly two decimals, `12.00` where serde would write `12.0`. Meant for
+/// `#[serde(serialize_with)]` on amounts already rounded with [`round_price`]; NaN and infinities
+/// become null like serde_json writes them
+pub fn serialize_cents<S: Serializer>(amount: &f64, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    if !amount.is_finite() {
+        return serializer.serialize_none();
+    }
+    RawValue::from_string(format!("{amount:.2}")).map_err(S::Error::custom)?.serialize(serializer)
+}
+
+/// [`serialize_cents`] for an amount that may be missing
+pub fn serialize_optional_cents<S: Serializer>(
+    amount: &Option<f64>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match amount {
+        Some(amount) => serialize_cents(amount, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// [`serialize_cents`] for every value of a map, like the per-currency totals
+pub fn serialize_cents_map<K: Serialize, S: Serializer>(
+    amounts: &BTreeMap<K, f64>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    struct Cents(f64);
+    impl Serialize for Cents {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            serialize_cents(&self.0, serializer)
+        }
+    }
+    serializer.collect_map(amounts.iter().map(|(key, amount)| (key, Cents(*amount))))
+}
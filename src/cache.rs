@@ -0,0 +1,56 @@
+//! OCR cache for `--cache-dir`. Tesseract's TSV output is stored per image, keyed by the image
+//! content and every setting that changes what Tesseract sees or reads, so re-running with new
+//! parse options skips OCR entirely.
+
+use crate::OcrOptions;
+use anyhow::{Context, Result};
+use image::DynamicImage;
+use receipt_analyzer::PreprocessOptions;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever preprocessing changes in a way its options don't capture
+const CACHE_VERSION: &str = "1";
+
+/// SHA-256 of the decoded image and the preprocessing and OCR settings. The pixels are hashed
+/// rather than the file so every page of a PDF gets its own key. The layout mode isn't part of
+/// the key since it only affects how the cached TSV is parsed.
+pub fn key(img: &DynamicImage, preprocess: &PreprocessOptions, ocr: &OcrOptions) -> String {
+    let mut hasher = Sha256::new();
+    let settings = format!(
+        "{}\n{}x{} {:?}\n{:?}\n{}\n{:?}\n{}\n",
+        CACHE_VERSION,
+        img.width(),
+        img.height(),
+        img.color(),
+        preprocess,
+        ocr.languages,
+        ocr.tessdata_dir,
+        ocr.psm
+    );
+    hasher.update(settings.as_bytes());
+    hasher.update(img.as_bytes());
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.tsv", key))
+}
+
+/// The cached TSV for `key`; unreadable entries count as a miss
+pub fn get(dir: &Path, key: &str) -> Option<String> {
+    std::fs::read_to_string(entry_path(dir, key)).ok()
+}
+
+/// Stores the TSV for `key`. Written to a temporary file first and renamed, so parallel workers
+/// and interrupted runs never leave a truncated entry behind
+pub fn put(dir: &Path, key: &str, tsv: &str) -> Result<()> {
+    let path = entry_path(dir, key);
+    let mut file = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("Failed to create a cache file in {}", dir.display()))?;
+    file.write_all(tsv.as_bytes())?;
+    file.persist(&path)
+        .with_context(|| format!("Failed to write cache entry {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! Receipt history for `--db`. Every receipt is stored once, keyed by the content of its file,
+//! so scanning the same folder again doesn't count anything twice.
+
+use anyhow::{Context, Result};
+use receipt_analyzer::{split_source, Receipt};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS receipts (
+        id INTEGER PRIMARY KEY,
+        source TEXT NOT NULL,
+        content_hash TEXT NOT NULL UNIQUE,
+        date TEXT,
+        store TEXT,
+        total REAL NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS items (
+        id INTEGER PRIMARY KEY,
+        receipt_id INTEGER NOT NULL REFERENCES receipts(id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        price REAL NOT NULL,
+        quantity INTEGER NOT NULL,
+        currency TEXT
+    );
+    CREATE INDEX IF NOT EXISTS items_receipt_id ON items(receipt_id);
+";
+
+/// Opens the database, creating it and its tables if needed
+pub fn open(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path).with_context(|| format!("Failed to open database {}", path.display()))?;
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    conn.execute_batch(SCHEMA).context("Failed to create the database tables")?;
+    Ok(conn)
+}
+
+/// How many receipts `record_receipts` stored and how many it found already recorded
+pub struct RecordCounts {
+    pub added: usize,
+    pub known: usize,
+}
+
+/// Stores receipts that aren't in the database yet, all in one transaction
+pub fn record_receipts(conn: &mut Connection, receipts: &[Receipt]) -> Result<RecordCounts> {
+    let mut counts = RecordCounts { added: 0, known: 0 };
+    let tx = conn.transaction()?;
+    for receipt in receipts {
+        let hash = content_hash(&receipt.source)?;
+        let known: Option<i64> = tx
+            .query_row("SELECT id FROM receipts WHERE content_hash = ?1", params![hash], |row| row.get(0))
+            .optional()?;
+        if known.is_some() {
+            counts.known += 1;
+            continue;
+        }
+
+        tx.execute(
+            "INSERT INTO receipts (source, content_hash, date, store, total) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                receipt.source.display().to_string(),
+                hash,
+                receipt.date.map(|date| date.to_string()),
+                receipt.store,
+                receipt.item_total()
+            ],
+        )?;
+        let receipt_id = tx.last_insert_rowid();
+        for item in &receipt.items {
+            tx.execute(
+                "INSERT INTO items (receipt_id, name, price, quantity, currency) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![receipt_id, item.name, item.price, item.quantity, item.currency],
+            )?;
+        }
+        counts.added += 1;
+    }
+    tx.commit()?;
+    Ok(counts)
+}
+
+/// SHA-256 of the file a receipt was read from, so renamed or moved copies are recognized as
+/// well. Pages of a multi-page PDF ("file.pdf#page=2") get the page number appended, images in a
+/// ZIP archive their entry name.
+fn content_hash(source: &Path) -> Result<String> {
+    let (file, part) = split_source(source);
+    let bytes = std::fs::read(&file).with_context(|| format!("Failed to read {} for hashing", file.display()))?;
+    let hash: String = Sha256::digest(&bytes).iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(match part {
+        Some(part) => format!("{}#{}", hash, part),
+        None => hash,
+    })
+}
+
+/// Spending recorded for one month and currency
+pub struct MonthlyTotal {
+    /// "2024-03", `None` for receipts without a date
+    pub month: Option<String>,
+    pub currency: String,
+    pub receipts: i64,
+    pub total: f64,
+}
+
+/// Item totals per month and currency, oldest first, undated receipts last
+pub fn monthly_totals(conn: &Connection) -> Result<Vec<MonthlyTotal>> {
+    let mut statement = conn.prepare(
+        "SELECT substr(receipts.date, 1, 7) AS month, COALESCE(items.currency, 'EUR') AS currency,
+                COUNT(DISTINCT receipts.id), SUM(items.price)
+         FROM items JOIN receipts ON receipts.id = items.receipt_id
+         GROUP BY month, currency
+         ORDER BY month IS NULL, month, currency",
+    )?;
+    let rows = statement.query_map(params![], |row| {
+        Ok(MonthlyTotal {
+            month: row.get(0)?,
+            currency: row.get(1)?,
+            receipts: row.get(2)?,
+            total: row.get(3)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
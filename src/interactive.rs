@@ -0,0 +1,116 @@
+//! `--interactive`: shows every product read from the receipts with the OCR line it came from
+//! and lets the user accept, edit or drop it before the products are merged.
+
+use anyhow::{Context, Result};
+use log::warn;
+use receipt_analyzer::{clean_product_name, parse_european_price, tidy_product_name, Product, Receipt};
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// What to do with the product on screen
+enum Answer {
+    Accept,
+    Edit,
+    Drop,
+    /// Accept this and every remaining product without asking
+    AcceptRest,
+}
+
+/// Asks about every product of `receipts`, removing dropped ones and renaming or repricing edited
+/// ones in place. Prompts go to stderr so the results on stdout stay clean. Without a terminal on
+/// stdin every product is kept as read
+pub fn review_receipts(receipts: &mut [Receipt], preserve_case: bool) -> Result<()> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        warn!("--interactive needs a terminal on stdin, keeping every product as read");
+        return Ok(());
+    }
+    let mut input = stdin.lock();
+    let count: usize = receipts.iter().map(|receipt| receipt.items.len()).sum();
+    let mut position = 0;
+    let mut accept_rest = false;
+
+    for receipt in receipts.iter_mut() {
+        let mut kept = Vec::new();
+        for mut product in std::mem::take(&mut receipt.items) {
+            if accept_rest {
+                kept.push(product);
+                continue;
+            }
+            position += 1;
+            eprintln!("\n[{}/{}] {}", position, count, receipt.source.display());
+            eprintln!("  Line:  {}", product.line);
+            eprintln!("  Name:  {}", product.name);
+            eprintln!("  Price: {:.2}", product.price);
+
+            match ask(&mut input)? {
+                Answer::Accept => kept.push(product),
+                Answer::Edit => {
+                    edit(&mut input, &mut product, preserve_case)?;
+                    kept.push(product);
+                }
+                Answer::Drop => {}
+                Answer::AcceptRest => {
+                    accept_rest = true;
+                    kept.push(product);
+                }
+            }
+        }
+        receipt.items = kept;
+    }
+    Ok(())
+}
+
+/// Prompts until one of the answers is given. Enter accepts, and so does the end of the input
+/// for everything left
+fn ask(input: &mut impl BufRead) -> Result<Answer> {
+    loop {
+        let Some(answer) = prompt(input, "[a]ccept, [e]dit, [d]rop, accept the [r]est? ")? else {
+            return Ok(Answer::AcceptRest);
+        };
+        match answer.to_lowercase().as_str() {
+            "" | "a" => return Ok(Answer::Accept),
+            "e" => return Ok(Answer::Edit),
+            "d" => return Ok(Answer::Drop),
+            "r" => return Ok(Answer::AcceptRest),
+            _ => eprintln!("Please answer a, e, d or r"),
+        }
+    }
+}
+
+/// Asks for a new name and price, keeping the current one on an empty answer. The name is
+/// normalized like the names read from receipts, so it merges with them
+fn edit(input: &mut impl BufRead, product: &mut Product, preserve_case: bool) -> Result<()> {
+    if let Some(name) = prompt(input, &format!("  Name [{}]: ", product.name))? {
+        let name = if preserve_case { tidy_product_name(&name) } else { clean_product_name(&name) };
+        if !name.is_empty() {
+            product.name = name;
+        }
+    }
+    loop {
+        let Some(price) = prompt(input, &format!("  Price [{:.2}]: ", product.price))? else {
+            return Ok(());
+        };
+        if price.is_empty() {
+            return Ok(());
+        }
+        match parse_european_price(&price) {
+            // "NaN" and "inf" parse as floats, and a typed price is never negative
+            Ok(parsed) if parsed.is_finite() && parsed >= 0.0 => {
+                product.price = parsed;
+                return Ok(());
+            }
+            _ => eprintln!("\"{}\" is not a price, e.g. 1,99", price),
+        }
+    }
+}
+
+/// Prints `question` to stderr and reads the trimmed answer, `None` at the end of the input
+fn prompt(input: &mut impl BufRead, question: &str) -> Result<Option<String>> {
+    eprint!("{}", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    if input.read_line(&mut answer).context("Failed to read the answer from stdin")? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(answer.trim().to_string()))
+}
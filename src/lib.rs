@@ -0,0 +1,253 @@
+//! Receipt parsing and product aggregation, independent of the OCR engine.
+//! The `receipt-analyzer` binary runs Tesseract and feeds the recognized lines into [`parse_receipt`].
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+mod aggregate;
+mod parse;
+mod preprocess;
+
+pub use aggregate::*;
+pub use parse::*;
+pub use preprocess::*;
+
+/// A line item read from a receipt
+#[derive(Clone, Debug)]
+pub struct Product {
+    /// Cleaned name, lowercase unless [`ParseOptions::preserve_case`] is set
+    pub name: String,
+    pub price: f64,
+    /// ISO code of the currency printed on the line, if any
+    pub currency: Option<&'static str>,
+    /// German VAT class letter printed after the price, if any
+    pub vat_class: Option<char>,
+    /// Number of units the line covers; `price` is always the line total
+    pub quantity: u32,
+    /// Mean OCR confidence (0–100) of the line the product was read from
+    pub confidence: f32,
+    /// Store named in the header of the receipt, if recognized
+    pub store: Option<String>,
+    /// Weighed amount of produce and deli items sold by the kilogram
+    pub weight: Option<Weight>,
+    /// The OCR line the product was read from, with its whitespace normalized
+    pub line: String,
+}
+
+/// The weight and price per kilogram printed for an item sold by weight
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Weight {
+    pub kg: f64,
+    pub price_per_kg: f64,
+}
+
+/// Everything read from one receipt
+#[derive(Clone, Debug)]
+pub struct Receipt {
+    /// File the receipt was read from, "file.pdf#page=2" for PDF pages. Empty when parsed
+    /// from text; the caller knows where the text came from
+    pub source: PathBuf,
+    /// Store named in the receipt header, if found
+    pub store: Option<String>,
+    /// First valid date printed on the receipt ("12.03.2024", "2024-03-12"), if any
+    pub date: Option<NaiveDate>,
+    pub items: Vec<Product>,
+    /// Total printed on the receipt ("Summe 12,00"), if found
+    pub printed_total: Option<f64>,
+    /// VAT lines ("MwSt 19% 2,34") as (rate in percent, amount), in receipt order
+    pub tax_breakdown: Vec<(f64, f64)>,
+    /// How the receipt was paid, from the first payment line ("Zahlung: Karte"), if any
+    pub payment: Option<PaymentMethod>,
+    /// Lines that were neither skipped nor matched by a product pattern, collected with
+    /// [`ParseOptions::collect_unmatched`]
+    pub unmatched: Vec<String>,
+    /// Lines that are not products, with the reason, collected with
+    /// [`ParseOptions::collect_skipped`]
+    pub skipped: Vec<(SkipReason, String)>,
+    /// The OCR lines the receipt was parsed from, joined with newlines
+    pub text: String,
+}
+
+/// Receipts whose text is more similar than this (normalized Levenshtein, 0–1) are the same
+/// receipt scanned twice
+pub const DUPLICATE_SIMILARITY: f64 = 0.95;
+
+/// Shown for receipts without a store name in the header
+pub const UNKNOWN_STORE: &str = "Unknown store";
+/// Shown for receipts without a valid date
+pub const NO_DATE: &str = "no date";
+
+impl Receipt {
+    /// Sum of the item prices
+    pub fn item_total(&self) -> f64 {
+        self.items.iter().map(|p| p.price).sum()
+    }
+
+    /// Whether this is a second scan of `other`: their OCR text is identical or more similar
+    /// than [`DUPLICATE_SIMILARITY`]. Receipts without text are never duplicates
+    pub fn is_duplicate_of(&self, other: &Receipt) -> bool {
+        if self.text.trim().is_empty() {
+            return false;
+        }
+        if self.text == other.text {
+            return true;
+        }
+        // The edit distance is at least the length difference, which rules out most pairs
+        // without comparing the texts
+        let (len, other_len) = (self.text.chars().count() as f64, other.text.chars().count() as f64);
+        (len - other_len).abs() <= (1.0 - DUPLICATE_SIMILARITY) * len.max(other_len)
+            && strsim::normalized_levenshtein(&self.text, &other.text) > DUPLICATE_SIMILARITY
+    }
+
+    /// Store, date and file name for headers: "REWE, 2024-03-12 (a.jpg)", with [`UNKNOWN_STORE`]
+    /// and [`NO_DATE`] for what wasn't found. Receipts parsed from text have no file name
+    pub fn heading(&self) -> String {
+        let store = self.store.as_deref().unwrap_or(UNKNOWN_STORE);
+        let date = self.date.map_or_else(|| NO_DATE.to_string(), |date| date.to_string());
+        match self.source.file_name() {
+            Some(file_name) => format!("{}, {} ({})", store, date, file_name.to_string_lossy()),
+            None => format!("{}, {}", store, date),
+        }
+    }
+}
+
+/// Splits the source of a receipt into the file it was read from and the part of that file:
+/// the "page=2" of "scan.pdf#page=2" or the "receipts/IMG_01.jpg" of "backup.zip#receipts/IMG_01.jpg"
+pub fn split_source(source: &Path) -> (PathBuf, Option<String>) {
+    let source = source.to_string_lossy();
+    if let Some((file, page)) = source.rsplit_once("#page=")
+        && page.chars().all(|c| c.is_ascii_digit())
+    {
+        return (PathBuf::from(file), Some(format!("page={}", page)));
+    }
+    // Lowercasing ASCII keeps every byte where it is, so the index is valid in `source` as well
+    if let Some(index) = source.to_ascii_lowercase().find(".zip#") {
+        let (file, entry) = source.split_at(index + ".zip".len());
+        return (PathBuf::from(file), Some(entry[1..].to_string()));
+    }
+    (PathBuf::from(source.as_ref()), None)
+}
+
+/// Which files in the input directories are read as receipts
+#[derive(Clone, Debug, Default)]
+pub struct FileSelection {
+    /// Lowercase extensions of the files read from a directory
+    pub extensions: Vec<String>,
+    /// Descend into subdirectories
+    pub recursive: bool,
+    /// Only read files modified after this time from a directory
+    pub modified_since: Option<SystemTime>,
+}
+
+/// Whether the extension of `path` is one of the lowercase `extensions`, ignoring case
+pub fn has_receipt_extension(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.contains(&ext.to_lowercase()))
+}
+
+/// Receipt files of every input in the order given: an input file itself whatever its extension or
+/// modification time, otherwise the files in the directory that `selection` picks. A file reached
+/// through two inputs, such as a directory and its subdirectory, is only listed once
+pub fn find_receipt_files(inputs: &[PathBuf], selection: &FileSelection) -> Result<Vec<PathBuf>> {
+    let mut receipt_paths = Vec::new();
+    let mut seen = HashSet::new();
+    for input in inputs {
+        let paths = if input.is_file() { vec![input.clone()] } else { find_directory_files(input, selection)? };
+        for path in paths {
+            if seen.insert(path.canonicalize().unwrap_or_else(|_| path.clone())) {
+                receipt_paths.push(path);
+            }
+        }
+    }
+    Ok(receipt_paths)
+}
+
+fn find_directory_files(dir: &Path, selection: &FileSelection) -> Result<Vec<PathBuf>> {
+    let mut receipt_paths = Vec::new();
+    // Depth 1 is the directory's own entries. Symlinks are not followed, the WalkDir default
+    let max_depth = if selection.recursive { usize::MAX } else { 1 };
+    for entry in WalkDir::new(dir).max_depth(max_depth) {
+        let entry = entry.context("Failed to read directory entry")?;
+        let path = entry.path();
+
+        if has_receipt_extension(path, &selection.extensions) {
+            if let Some(since) = selection.modified_since {
+                let modified = entry.metadata()?.modified()?;
+                if modified <= since {
+                    continue;
+                }
+            }
+            receipt_paths.push(path.to_path_buf());
+        }
+    }
+    Ok(receipt_paths)
+}
+
+/// Reads the receipts of one file for [`process_receipts_iter`]; the binary implements it with
+/// Tesseract
+pub trait ReceiptReader: Sync {
+    /// Every receipt in the file at `path`: one per image, PDF page or archive entry. Errors
+    /// should name the file or page they belong to
+    fn read_file(&self, path: &Path) -> Vec<Result<Receipt>>;
+
+    /// Called with the number of files found, before the first one is read
+    fn started(&self, _files: usize) {}
+}
+
+/// Files read per batch and worker thread by [`process_receipts_iter`]
+const BATCH_FILES_PER_THREAD: usize = 4;
+
+/// Reads the receipt files under `inputs` (see [`find_receipt_files`]) with `reader`, yielding
+/// every receipt in directory order. Files are read in parallel on the rayon global pool, one
+/// batch at a time as the iterator is advanced, so only a batch of receipts is held in memory
+/// however large the directory is, and callers can fold them into a running total
+pub fn process_receipts_iter<'a, R: ReceiptReader>(
+    inputs: &[PathBuf],
+    selection: &FileSelection,
+    reader: &'a R,
+) -> Result<impl Iterator<Item = Result<Receipt>> + 'a> {
+    let receipt_paths = find_receipt_files(inputs, selection)?;
+    reader.started(receipt_paths.len());
+
+    let batch_size = rayon::current_num_threads() * BATCH_FILES_PER_THREAD;
+    let mut receipt_paths = receipt_paths.into_iter();
+    let batches = std::iter::from_fn(move || {
+        let batch: Vec<PathBuf> = receipt_paths.by_ref().take(batch_size).collect();
+        if batch.is_empty() {
+            return None;
+        }
+        // Collected in batch order, so the output doesn't depend on thread scheduling
+        let results: Vec<Vec<Result<Receipt>>> = batch.par_iter().map(|path| reader.read_file(path)).collect();
+        Some(results)
+    });
+    Ok(batches.flatten().flatten())
+}
+
+/// A line of OCR output with the engine's mean word confidence (0–100)
+#[derive(Clone, Debug)]
+pub struct OcrLine {
+    pub text: String,
+    pub confidence: f32,
+}
+
+/// How a receipt was paid
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PaymentMethod {
+    Card,
+    Cash,
+}
+
+impl PaymentMethod {
+    pub fn label(self) -> &'static str {
+        match self {
+            PaymentMethod::Card => "card",
+            PaymentMethod::Cash => "cash",
+        }
+    }
+}
@@ -1,366 +1,1627 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
-use image::{ImageBuffer, Luma, DynamicImage};
-use prettytable::{format, Cell, Row, Table};
+use clap::{ArgAction, Parser, ValueEnum};
+use image::{DynamicImage, ImageFormat};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, error, info, warn, LevelFilter};
+use output::{
+    display_category_summary, display_layout, display_monthly_report, display_stats, display_tax_summary,
+    display_top_receipts, display_unmatched, join_paths, resolve_output_format, Aggregation, DisplayOptions, GroupBy,
+    Layout, OutputFormat, RunSummary,
+};
+use pdfium_render::prelude::{Pdfium, PdfiumError, PdfiumInternalError, PdfRenderConfig};
+use receipt_analyzer::{
+    aggregate_products, find_receipt_files, parse_receipt, parse_skip_regex, parse_skip_words, parse_tesseract_tsv,
+    parse_tesseract_tsv_spatial, preprocess_image_with_options, process_receipts_iter, processed_image_name, stats,
+    AggregateOptions, Binarization, Categories, FileSelection, JaroNameMatcher, LevenshteinNameMatcher, Locale,
+    NameMatcher, ParseOptions, PaymentMethod, PreprocessOptions, Product, Receipt, ReceiptReader, Rounding,
+    SkimNameMatcher, SkipMatcher, Synonyms, TokenSetNameMatcher, DEFAULT_ADAPTIVE_WINDOW, DEFAULT_CONTRAST,
+    DEFAULT_CREDIT_MARKERS, DEFAULT_CURRENCY, DEFAULT_FUZZY_THRESHOLD, DEFAULT_GAMMA, DEFAULT_MAX_NAME_LEN,
+    DEFAULT_MAX_PRICE, DEFAULT_MIN_NAME_LEN,
+};
 use regex::Regex;
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use timings::{Stage, Timings};
 use tesseract::Tesseract;
-use walkdir::WalkDir;
+
+mod cache;
+mod db;
+mod interactive;
+mod osd;
+mod output;
+mod timings;
+mod watch;
 
 #[derive(Parser)]
 #[command(name = "receipt-analyzer")]
 #[command(about = "Analyze receipt images and extract product prices")]
 struct Args {
-    /// Directory containing receipt images
+    /// Receipt image or PDF, or a directory containing them. Repeat it to combine several
+    /// directories and files into one report
+    #[arg(short, long, value_name = "PATH", visible_alias = "dir", short_alias = 'd', action = ArgAction::Append, required_unless_present_any = ["report", "stdin"])]
+    input: Vec<PathBuf>,
+
+    /// Read a single image from stdin instead of --input, e.g. `cat receipt.png | receipt-analyzer --stdin`
+    #[arg(long, conflicts_with_all = ["input", "watch", "dry_run", "db"])]
+    stdin: bool,
+
+    /// Also scan the subdirectories of the input directory. By default only the files directly
+    /// inside it are processed
     #[arg(short, long)]
-    dir: String,
-}
+    recursive: bool,
+
+    /// File extensions processed in the input directory, compared ignoring case. HEIC photos
+    /// need a build with the `heic` feature
+    #[arg(long, value_name = "EXTS", value_delimiter = ',', default_values_t = DEFAULT_EXTENSIONS.iter().map(|ext| ext.to_string()))]
+    extensions: Vec<String>,
+
+    /// Fuzzy-match algorithm used to merge similar product names
+    #[arg(long, value_enum, default_value_t = MatcherKind::Skim, visible_alias = "match-strategy")]
+    matcher: MatcherKind,
+
+    /// Rounding applied to displayed prices and totals (default: half-up)
+    #[arg(long, value_enum, default_value_t = RoundingMode::HalfUp)]
+    rounding: RoundingMode,
+
+    /// Symbol printed after amounts in the default currency, which also covers items without a
+    /// detected currency. Items with a detected currency keep its own symbol
+    #[arg(long, value_name = "SYMBOL", default_value = "€")]
+    currency_symbol: String,
+
+    /// Print amounts with a decimal comma, 3,50€ instead of 3.50€. JSON and CSV keep the point
+    #[arg(long)]
+    decimal_comma: bool,
+
+    /// Add net price and VAT columns, computed from each item's VAT class
+    #[arg(long)]
+    show_net: bool,
+
+    /// What to list per product before its total: the units and average unit price (sum), the
+    /// units alone (count), the mean unit price (avg) or the lowest and highest (minmax)
+    #[arg(long, value_enum, default_value_t = Aggregation::Sum)]
+    aggregate: Aggregation,
+
+    /// Print one table per receipt image with its subtotal instead of merging all receipts, headed
+    /// by its store, date and file name. JSON adds the receipts as "receipts" sections
+    #[arg(long, conflicts_with = "group_by")]
+    per_receipt: bool,
+
+    /// Print one table per group instead of a single product list
+    #[arg(long, value_enum)]
+    group_by: Option<GroupBy>,
+
+    /// Merge product names whose similarity (0–100) is above this. Around 70–90 works for most
+    /// receipts; raise it if distinct products get merged, lower it if OCR variants stay apart
+    #[arg(long, value_name = "SCORE", default_value_t = DEFAULT_FUZZY_THRESHOLD, value_parser = clap::value_parser!(i64).range(0..=100))]
+    fuzzy_threshold: i64,
+
+    /// Compare each product against at most the N most recently added names when merging.
+    /// A heuristic cap on runtime for large runs; it can miss merges with older names
+    #[arg(long, value_name = "N")]
+    max_compare: Option<usize>,
+
+    /// Skip OCR lines matching this regex, in addition to the built-in keywords (repeatable)
+    #[arg(long, value_name = "PATTERN", value_parser = parse_skip_regex)]
+    skip_regex: Vec<Regex>,
+
+    /// Add the substrings in FILE (one per line, "#" starts a comment) to the built-in skip keywords.
+    /// Repeatable; the keywords of every file are skipped
+    #[arg(long, value_name = "FILE")]
+    skip_words: Vec<PathBuf>,
+
+    /// Use only the --skip-words keywords instead of adding them to the built-in list
+    #[arg(long, requires = "skip_words")]
+    replace_skip_words: bool,
+
+    /// Rename product aliases before merging, one "canonical = alias1, alias2" rule per line.
+    /// Names not listed are still merged by the fuzzy matcher. Repeatable; where two files list the
+    /// same alias, the later file wins
+    #[arg(long, value_name = "FILE")]
+    synonyms: Vec<PathBuf>,
+
+    /// Sum the products per category after the results, one "category = keyword1, keyword2" rule
+    /// per line. Keywords match case-insensitive substrings of the name; the first matching rule wins.
+    /// Repeatable; the rules of later files are checked first and take over keywords they repeat
+    #[arg(long, value_name = "FILE")]
+    categories: Vec<PathBuf>,
+
+    /// Keep product names in the casing printed on the receipt ("Löwenbräu Original") instead of
+    /// lowercasing them. Names still merge regardless of case
+    #[arg(long)]
+    preserve_case: bool,
+
+    /// Log every skipped OCR line to stderr as "FILE<TAB>REASON<TAB>RULE<TAB>LINE", e.g.
+    /// "a.jpg<TAB>total<TAB>keyword "summe"<TAB>Summe 12,00"
+    #[arg(long, alias = "explain-skip")]
+    explain_skips: bool,
+
+    /// After the table, list the lines of each receipt that were neither skipped nor matched
+    /// by a product pattern, to find receipt layouts the parser doesn't know yet
+    #[arg(long)]
+    show_unmatched: bool,
+
+    /// Markers after an amount that turn it into a credit ("5,00-", "5,00 CR", "5,00 H")
+    #[arg(long, value_name = "MARKERS", value_delimiter = ',', default_values_t = DEFAULT_CREDIT_MARKERS.iter().map(|m| m.to_string()))]
+    credit_markers: Vec<String>,
+
+    /// Drop items priced below this amount (inclusive bound)
+    #[arg(long, value_name = "AMOUNT", default_value_t = 0.0)]
+    min_price: f64,
+
+    /// Drop items priced above this amount (inclusive bound); higher prices are usually OCR errors
+    #[arg(long, value_name = "AMOUNT", default_value_t = DEFAULT_MAX_PRICE)]
+    max_price: f64,
+
+    /// Drop items read from OCR lines with a mean word confidence below this (0–100)
+    #[arg(long, value_name = "PERCENT", default_value_t = 0.0)]
+    min_confidence: f32,
+
+    /// Shortest product name in characters; 2 accepts names like "Ei"
+    #[arg(long, value_name = "CHARS", default_value_t = DEFAULT_MIN_NAME_LEN)]
+    min_name_len: usize,
+
+    /// Longest product name in characters; longer names are cut short or not read at all
+    #[arg(long, value_name = "CHARS", default_value_t = DEFAULT_MAX_NAME_LEN)]
+    max_name_len: usize,
+
+    /// Warn when the item prices of a receipt differ from its printed total by more than this
+    #[arg(long, value_name = "AMOUNT", default_value_t = 0.02)]
+    total_tolerance: f64,
+
+    /// Only process images modified after this point: the path of a file (its modification time
+    /// is used), Unix seconds, or a UTC date/time like 2024-03-01 or 2024-03-01T18:30:00
+    #[arg(long, value_name = "TIMESTAMP|FILE", value_parser = parse_modified_since)]
+    modified_since: Option<SystemTime>,
+
+    /// Crop photos to the receipt before OCR, cutting away the table around it. Images whose
+    /// receipt outline isn't clearly visible on all four sides are left whole
+    #[arg(long)]
+    autocrop: bool,
+
+    /// Rescale images before OCR so the text height approaches what it would be at this DPI
+    /// (Tesseract works best around 300). Off by default
+    #[arg(long, value_name = "DPI")]
+    target_dpi: Option<u32>,
+
+    /// Straighten receipts photographed at an angle (up to 15°) before OCR. Slower, so off by default
+    #[arg(long)]
+    deskew: bool,
 
-#[derive(Debug)]
-struct Product {
-    name: String,
-    price: f64,
+    /// Turn sideways and upside-down photos upright before OCR, using Tesseract's orientation
+    /// detection. Needs osd.traineddata; images with too little text to tell are left as they are
+    #[arg(long)]
+    auto_rotate: bool,
+
+    /// How the grayscale image is prepared for OCR
+    #[arg(long, value_enum, default_value_t = BinarizeMode::Contrast)]
+    binarize: BinarizeMode,
+
+    /// Side in pixels of the square neighborhood each pixel is compared with for --binarize
+    /// adaptive; a few text lines high works best
+    #[arg(long, value_name = "PX", default_value_t = DEFAULT_ADAPTIVE_WINDOW)]
+    window: u32,
+
+    /// Contrast factor around mid-gray for --binarize contrast; lower it for bright scans that
+    /// get blown out
+    #[arg(long, value_name = "FACTOR", default_value_t = DEFAULT_CONTRAST)]
+    contrast: f32,
+
+    /// Gamma correction before the contrast step for --binarize contrast; below 1 darkens faint
+    /// print, above 1 brightens dark photos
+    #[arg(long, value_name = "GAMMA", default_value_t = DEFAULT_GAMMA)]
+    gamma: f32,
+
+    /// Sharpen out-of-focus photos with an unsharp mask of this strength before the contrast
+    /// step; 0.5 to 2 works for most, 0 (the default) turns it off
+    #[arg(long, value_name = "AMOUNT", default_value_t = 0.0)]
+    sharpen: f32,
+
+    /// When an image yields no products, run OCR once more on an Otsu-binarized copy. Doubles
+    /// the OCR time of such images, so off by default
+    #[arg(long)]
+    retry_preprocess: bool,
+
+    /// Save every preprocessed image, as handed to Tesseract, in this directory as NAME-HASH.png
+    /// instead of deleting it after OCR. HASH tells receipts with the same name apart
+    #[arg(long, value_name = "DIR")]
+    keep_processed: Option<PathBuf>,
+
+    /// Store Tesseract's output per image in this directory and reuse it on later runs with the
+    /// same preprocessing and OCR settings, so only the parsing is redone. --keep-processed
+    /// only saves images that weren't in the cache
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Tesseract languages to read, joined with "+" ("fra", "spa+eng"). Each needs its
+    /// LANG.traineddata file in the language data directory
+    #[arg(long, value_name = "LANGS", default_value = DEFAULT_OCR_LANGUAGES)]
+    lang: String,
+
+    /// Receipt languages whose non-product words ("TVA", "gracias") are skipped, comma-separated.
+    /// Defaults to the languages of --lang; German and English words are always skipped
+    #[arg(long, value_enum, value_name = "LOCALES", value_delimiter = ',')]
+    locale: Vec<LocaleArg>,
+
+    /// Directory containing the Tesseract language data (deu.traineddata, eng.traineddata).
+    /// Defaults to Tesseract's own search path (TESSDATA_PREFIX or its install location)
+    #[arg(long, value_name = "DIR")]
+    tessdata_dir: Option<PathBuf>,
+
+    /// Tesseract page segmentation mode (0–13). The default of 4, a single column of text of
+    /// varying size, suits most receipts; try 6 for very uniform ones
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_PSM, value_parser = clap::value_parser!(u8).range(0..=13))]
+    psm: u8,
+
+    /// How OCR words are assembled into lines before parsing
+    #[arg(long, value_enum, default_value_t = LayoutMode::Regex)]
+    layout: LayoutMode,
+
+    /// Number of images to OCR in parallel (default: one per CPU core)
+    #[arg(long, short, value_name = "N")]
+    jobs: Option<NonZeroUsize>,
+
+    /// Give up on an image after this many seconds and report it as failed, so one corrupt file
+    /// can't stall the batch. No limit by default
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: Option<u64>,
+
+    /// Print tables without colors. Colors are also off when stdout isn't a terminal or NO_COLOR is set
+    #[arg(long)]
+    no_color: bool,
+
+    /// Only list the N products with the highest totals; the grand total still covers all of
+    /// them. 0 lists every product
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// After the results, list the N receipts with the highest totals with their store and date
+    /// (default 3). JSON reports them as "top_receipts"
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "3")]
+    highlight_receipts: Option<usize>,
+
+    /// After the results, print the number of products and items and the mean, median and
+    /// highest item price
+    #[arg(long)]
+    stats: bool,
+
+    /// Print the time spent preprocessing, in OCR and parsing, per image and in total, to stderr
+    /// after the results
+    #[arg(long)]
+    timings: bool,
+
+    /// Output format for the results (default: table, or inferred from the --output extension).
+    /// json and csv without --output print only the results to stdout
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Before merging, show every product with the OCR line it was read from and accept, edit or
+    /// drop it. Needs a terminal on stdin; otherwise every product is kept as read
+    #[arg(long, conflicts_with_all = ["watch", "stdin"])]
+    interactive: bool,
+
+    /// Stop at the first image or PDF that can't be processed instead of reporting it and going on
+    #[arg(long)]
+    strict: bool,
+
+    /// Keep receipts whose OCR text is nearly identical to an earlier one's instead of skipping
+    /// them as the same receipt scanned twice
+    #[arg(long)]
+    allow_duplicates: bool,
+
+    /// Only report errors: no progress bar, warnings or notes
+    #[arg(long, short, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log every processed image and what was read from it to stderr; twice (-vv) also the OCR
+    /// text. RUST_LOG overrides the level
+    #[arg(long, short, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Write the json or csv results to this file instead of stdout
+    #[arg(long, short, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Record every receipt and its items in this SQLite database. Receipts already recorded,
+    /// recognized by the content of their file, are skipped
+    #[arg(long, value_name = "PATH")]
+    db: Option<PathBuf>,
+
+    /// Print the spending per month recorded in --db instead of scanning receipts
+    #[arg(long, requires = "db")]
+    report: bool,
+
+    /// After the first scan, keep watching the input directory and add receipts as they appear.
+    /// Stop with Ctrl-C
+    #[arg(long)]
+    watch: bool,
+
+    /// List the files that would be processed, honoring --extensions, --recursive and
+    /// --modified-since, and exit without running OCR. The count goes to stderr
+    #[arg(long, conflicts_with = "watch")]
+    dry_run: bool,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    print_config: bool,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum MatcherKind {
+    /// Skim subsequence matching (default)
+    Skim,
+    /// Jaro-Winkler similarity, tends to work best for short product names
+    Jaro,
+    /// Normalized Levenshtein ratio
+    Levenshtein,
+    /// Levenshtein ratio of the words sorted alphabetically, merges reordered names
+    Tokenset,
+}
 
-    println!("Analyzing receipts in: {}", args.dir);
+impl MatcherKind {
+    fn build(self) -> Box<dyn NameMatcher> {
+        match self {
+            MatcherKind::Skim => Box::new(SkimNameMatcher::default()),
+            MatcherKind::Jaro => Box::new(JaroNameMatcher),
+            MatcherKind::Levenshtein => Box::new(LevenshteinNameMatcher),
+            MatcherKind::Tokenset => Box::new(TokenSetNameMatcher),
+        }
+    }
+}
 
-    let products = process_receipt_directory(&args.dir)?;
-    let aggregated = aggregate_products(products);
-    display_results(aggregated);
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum BinarizeMode {
+    /// Black and white at the Otsu threshold, helps with faded thermal receipts
+    Otsu,
+    /// Black and white against the mean of each pixel's --window neighborhood, for photos with
+    /// shadows or uneven lighting
+    Adaptive,
+    /// Gamma correction and a contrast stretch, see --gamma and --contrast (default)
+    Contrast,
+    /// Grayscale only
+    None,
+}
 
-    Ok(())
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum RoundingMode {
+    /// Round half away from zero: 0,125 -> 0,13
+    HalfUp,
+    /// Banker's rounding, round half to even: 0,125 -> 0,12
+    HalfEven,
+    /// Drop fractional cents: 0,129 -> 0,12
+    Truncate,
 }
 
-fn process_receipt_directory(dir_path: &str) -> Result<Vec<Product>> {
-    let mut all_products = Vec::new();
-    let image_extensions = ["jpg", "jpeg", "png", "tiff", "bmp"];
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum LocaleArg {
+    /// German (always on)
+    De,
+    /// English (always on)
+    En,
+    /// French: "sous-total", "TVA", "merci", ...
+    Fr,
+    /// Spanish: "IVA", "gracias", "cambio", ...
+    Es,
+}
 
-    for entry in WalkDir::new(dir_path) {
-        let entry = entry.context("Failed to read directory entry")?;
-        let path = entry.path();
+impl From<LocaleArg> for Locale {
+    fn from(locale: LocaleArg) -> Self {
+        match locale {
+            LocaleArg::De => Locale::German,
+            LocaleArg::En => Locale::English,
+            LocaleArg::Fr => Locale::French,
+            LocaleArg::Es => Locale::Spanish,
+        }
+    }
+}
 
-        if let Some(ext) = path.extension() {
-            if image_extensions.contains(&ext.to_str().unwrap_or("").to_lowercase().as_str()) {
-                println!("Processing: {}", path.display());
+impl From<RoundingMode> for Rounding {
+    fn from(mode: RoundingMode) -> Self {
+        match mode {
+            RoundingMode::HalfUp => Rounding::HalfUp,
+            RoundingMode::HalfEven => Rounding::HalfEven,
+            RoundingMode::Truncate => Rounding::Truncate,
+        }
+    }
+}
 
-                match extract_products_from_image(path) {
-                    Ok(mut products) => {
-                        all_products.append(&mut products);
-                    }
-                    Err(e) => {
-                        eprintln!("Error processing {}: {}", path.display(), e);
-                    }
-                }
-            }
+impl From<BinarizeMode> for Binarization {
+    fn from(mode: BinarizeMode) -> Self {
+        match mode {
+            BinarizeMode::Otsu => Binarization::Otsu,
+            BinarizeMode::Adaptive => Binarization::Adaptive,
+            BinarizeMode::Contrast => Binarization::Contrast,
+            BinarizeMode::None => Binarization::None,
         }
     }
+}
 
-    Ok(all_products)
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LayoutMode {
+    /// Tesseract's own lines, parsed by the line patterns (default)
+    Regex,
+    /// Rows rebuilt from word positions, split into name and price columns at wide gaps.
+    /// Helps when prices sit far to the right or the receipt has two columns
+    Spatial,
 }
 
-fn extract_products_from_image(image_path: &Path) -> Result<Vec<Product>> {
-    // Load and preprocess image for better OCR
-    let img = image::open(image_path)?;
-    let processed_img = preprocess_image(img);
+/// Settings that control which images are processed and how
+struct ScanOptions {
+    /// Which files of the input directories are processed
+    files: FileSelection,
+    preprocess: PreprocessOptions,
+    /// Retry images without products with Otsu binarization
+    retry_preprocess: bool,
+    /// Directory the preprocessed images are saved in, deleted after OCR if `None`
+    keep_processed: Option<PathBuf>,
+    /// Directory of cached OCR output, OCR always runs if `None`
+    cache_dir: Option<PathBuf>,
+    ocr: OcrOptions,
+    /// Largest difference between the item sum and the printed total that isn't reported
+    total_tolerance: f64,
+    /// Longest time an image may take before it is abandoned, unlimited if `None`
+    timeout: Option<Duration>,
+    /// Where the preprocessed images for Tesseract are written; removed at the end of the run,
+    /// together with the files of abandoned images
+    temp_dir: PathBuf,
+    /// Advanced once per file by `process_receipts`, hidden unless stderr is a terminal. Workers
+    /// log through `suspend` so their messages don't tear the bar
+    progress: ProgressBar,
+    /// Fail on the first file that can't be processed
+    strict: bool,
+    /// Keep receipts that are second scans of an earlier one
+    allow_duplicates: bool,
+    /// Time spent per image and stage, collected for --timings
+    timings: Option<Timings>,
+}
 
-    // Save processed image temporarily
-    let temp_path = format!("/tmp/processed_{}", image_path.file_name().unwrap().to_str().unwrap());
-    processed_img.save(&temp_path)?;
+/// How Tesseract is set up
+struct OcrOptions {
+    /// Tesseract language codes joined with "+"
+    languages: String,
+    /// Language data directory, Tesseract's default search path if `None`
+    tessdata_dir: Option<PathBuf>,
+    /// Page segmentation mode (`tessedit_pageseg_mode`)
+    psm: u8,
+    /// How words are assembled into lines
+    layout: LayoutMode,
+    /// Detect the page orientation and turn images upright before OCR
+    auto_rotate: bool,
+}
 
-    // Use German language for better OCR on German receipts
-    let mut tesseract = Tesseract::new(None, Some("deu+eng"))?
-        .set_image(&temp_path)?;
+/// Receipts found under the input paths and how many files could be processed
+#[derive(Default)]
+struct DirectoryScan {
+    /// Every successfully processed image or PDF page, in directory order
+    receipts: Vec<Receipt>,
+    succeeded: usize,
+    failed: usize,
+    /// Receipts skipped as second scans of an earlier one
+    duplicates: usize,
+}
 
-    let text = tesseract.get_text()?;
+fn main() -> Result<ExitCode> {
+    let args = Args::parse();
+    init_logging(&args);
 
-    // Clean up temp file
-    std::fs::remove_file(&temp_path).ok();
+    if args.print_config {
+        print_config(&args);
+        return Ok(ExitCode::SUCCESS);
+    }
 
-    parse_receipt_text(&text)
-}
+    if args.report {
+        let db_path = args.db.as_deref().context("--report needs --db")?;
+        let conn = db::open(db_path)?;
+        // Only the amount settings apply to the report
+        let options = DisplayOptions {
+            rounding: args.rounding.into(),
+            currency_symbol: &args.currency_symbol,
+            decimal_comma: args.decimal_comma,
+            ..DisplayOptions::default()
+        };
+        display_monthly_report(&db::monthly_totals(&conn)?, options);
+        return Ok(ExitCode::SUCCESS);
+    }
+    let inputs = if args.stdin { vec![PathBuf::from(STDIN_SOURCE)] } else { args.input.clone() };
+    if inputs.is_empty() {
+        anyhow::bail!("--input is required");
+    }
+
+    let format = resolve_output_format(args.format, args.output.as_deref())?;
+    if format != OutputFormat::Table && (args.group_by.is_some() || args.watch || args.show_unmatched) {
+        anyhow::bail!("--group-by, --watch and --show-unmatched are only available with --format table");
+    }
+    if args.per_receipt && format == OutputFormat::Csv {
+        anyhow::bail!("--per-receipt is only available with --format table, json or html");
+    }
+    if args.watch && !inputs.iter().all(|input| input.is_dir()) {
+        anyhow::bail!("--watch needs directories as --input");
+    }
+    // Keep stdout machine-readable when json or csv go there
+    let human_output = format == OutputFormat::Table || args.output.is_some();
+    if !human_output && args.highlight_receipts.is_some() && format != OutputFormat::Json {
+        anyhow::bail!("--highlight-receipts needs --format table or json, or --output");
+    }
+    if !human_output && args.stats && format != OutputFormat::Json {
+        anyhow::bail!("--stats needs --format table or json, or --output");
+    }
+    if !human_output && !args.categories.is_empty() {
+        anyhow::bail!("--categories needs --format table or --output");
+    }
+
+    if args.min_price > args.max_price {
+        anyhow::bail!("--min-price {} is above --max-price {}", args.min_price, args.max_price);
+    }
+    if args.min_name_len == 0 {
+        anyhow::bail!("--min-name-len must be at least 1");
+    }
+    if args.min_name_len > args.max_name_len {
+        anyhow::bail!("--min-name-len {} is above --max-name-len {}", args.min_name_len, args.max_name_len);
+    }
+    if args.contrast.is_nan() || args.contrast < 0.0 {
+        anyhow::bail!("--contrast must not be negative");
+    }
+    if args.gamma.is_nan() || args.gamma <= 0.0 {
+        anyhow::bail!("--gamma must be above 0");
+    }
+    if args.sharpen.is_nan() || args.sharpen < 0.0 {
+        anyhow::bail!("--sharpen must not be negative");
+    }
+    if args.window < 3 {
+        anyhow::bail!("--window must be at least 3 pixels");
+    }
+
+    info!("Analyzing receipts in: {}", join_paths(&inputs));
+
+    let locales = locales(&args);
+    let mut skip = SkipMatcher { patterns: args.skip_regex, ..SkipMatcher::default() };
+    for locale in locales {
+        skip.add_locale(locale);
+    }
+    if args.replace_skip_words {
+        skip.keywords.clear();
+        skip.words.clear();
+    }
+    for path in &args.skip_words {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read skip words from {}", path.display()))?;
+        skip.keywords.extend(parse_skip_words(&text));
+    }
+
+    // Files are merged in the order given, later ones overriding earlier ones
+    let mut synonyms = Synonyms::default();
+    for path in &args.synonyms {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read synonyms from {}", path.display()))?;
+        synonyms.merge(Synonyms::parse(&text).with_context(|| format!("Invalid synonyms file {}", path.display()))?);
+    }
+    let mut categories: Option<Categories> = None;
+    for path in &args.categories {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read categories from {}", path.display()))?;
+        let file = Categories::parse(&text).with_context(|| format!("Invalid categories file {}", path.display()))?;
+        categories.get_or_insert_default().merge(file);
+    }
+
+    let parse_options = Arc::new(ParseOptions {
+        skip,
+        collect_skipped: args.explain_skips,
+        collect_unmatched: args.show_unmatched,
+        credit_markers: args.credit_markers,
+        min_price: args.min_price,
+        max_price: args.max_price,
+        min_confidence: args.min_confidence,
+        min_name_len: args.min_name_len,
+        max_name_len: args.max_name_len,
+        synonyms,
+        preserve_case: args.preserve_case,
+    });
+    // The bar would be torn by the lines logged with -v, which list the files anyway
+    let show_progress = !args.quiet && args.verbose == 0 && !args.stdin && std::io::stderr().is_terminal();
+    let temp_dir = tempfile::Builder::new()
+        .prefix(TEMP_FILE_PREFIX)
+        .tempdir()
+        .context("Failed to create a temporary directory")?;
+    let scan_options = Arc::new(ScanOptions {
+        files: FileSelection {
+            // "JPG" and ".jpg" work as well
+            extensions: args.extensions.iter().map(|ext| ext.trim().trim_start_matches('.').to_lowercase()).collect(),
+            recursive: args.recursive,
+            modified_since: args.modified_since,
+        },
+        preprocess: PreprocessOptions {
+            autocrop: args.autocrop,
+            target_dpi: args.target_dpi,
+            deskew: args.deskew,
+            binarization: args.binarize.into(),
+            contrast: args.contrast,
+            gamma: args.gamma,
+            sharpen: args.sharpen,
+            adaptive_window: args.window,
+        },
+        retry_preprocess: args.retry_preprocess,
+        keep_processed: args.keep_processed.clone(),
+        cache_dir: args.cache_dir.clone(),
+        ocr: OcrOptions {
+            languages: args.lang.clone(),
+            tessdata_dir: args.tessdata_dir.clone(),
+            psm: args.psm,
+            layout: args.layout,
+            auto_rotate: args.auto_rotate,
+        },
+        total_tolerance: args.total_tolerance,
+        timeout: args.timeout.map(Duration::from_secs),
+        temp_dir: temp_dir.path().to_path_buf(),
+        progress: if show_progress { ProgressBar::new(0) } else { ProgressBar::hidden() },
+        strict: args.strict,
+        allow_duplicates: args.allow_duplicates,
+        timings: args.timings.then(Timings::default),
+    });
+    if args.dry_run {
+        let receipt_paths = find_receipt_files(&inputs, &scan_options.files)?;
+        for path in &receipt_paths {
+            println!("{}", path.display());
+        }
+        eprintln!("{} files would be processed", receipt_paths.len());
+        return Ok(ExitCode::SUCCESS);
+    }
+    for dir in [&scan_options.keep_processed, &scan_options.cache_dir].into_iter().flatten() {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    // Fail once up front instead of for every image when the language data is missing
+    check_language_data(&scan_options.ocr)?;
+    new_tesseract(&scan_options.ocr)?;
+    // OCR is the bottleneck and runs in parallel; every task creates its own Tesseract instance
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs.map_or(0, NonZeroUsize::get))
+        .build_global()
+        .context("Failed to start worker threads")?;
+    let scan_started = Instant::now();
+    let mut scan = if args.stdin {
+        process_stdin(&parse_options, &scan_options)?
+    } else {
+        process_receipts(&inputs, &parse_options, &scan_options)?
+    };
+    let scan_time = scan_started.elapsed();
+    if args.interactive {
+        interactive::review_receipts(&mut scan.receipts, args.preserve_case)?;
+    }
+    let mut conn = args.db.as_deref().map(db::open).transpose()?;
+    if let (Some(conn), Some(db_path)) = (&mut conn, &args.db) {
+        record_in_db(conn, db_path, &scan.receipts)?;
+    }
+    let products: Vec<Product> = scan
+        .receipts
+        .iter()
+        .flat_map(|receipt| receipt.items.iter().cloned())
+        .collect();
+    let item_count = products.len();
+    let detected_currencies = products.iter().filter(|p| p.currency.is_some()).count();
+    let currencies: BTreeSet<&str> = products.iter().filter_map(|p| p.currency).collect();
+    // Per currency as well, adding up "$" and "€" amounts is meaningless
+    let mut spend_by_payment: BTreeMap<(Option<PaymentMethod>, &str), f64> = BTreeMap::new();
+    for receipt in &scan.receipts {
+        for product in &receipt.items {
+            let currency = product.currency.unwrap_or(DEFAULT_CURRENCY);
+            *spend_by_payment.entry((receipt.payment, currency)).or_default() += product.price;
+        }
+    }
+
+    let display_options = DisplayOptions {
+        format,
+        output: args.output.as_deref(),
+        inputs: &inputs,
+        rounding: args.rounding.into(),
+        show_net: args.show_net,
+        aggregation: args.aggregate,
+        top: args.top.filter(|&n| n > 0),
+        // https://no-color.org: any non-empty NO_COLOR turns colors off
+        color: !args.no_color
+            && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+            && std::io::stdout().is_terminal(),
+        currency_symbol: &args.currency_symbol,
+        decimal_comma: args.decimal_comma,
+        stats: args.stats,
+        summary: RunSummary { succeeded: scan.succeeded, failed: scan.failed, duplicates: scan.duplicates },
+        highlight_receipts: args.highlight_receipts,
+    };
+    let matcher = args.matcher.build();
+    let aggregate_options = AggregateOptions {
+        threshold: args.fuzzy_threshold,
+        max_compare: args.max_compare,
+    };
+    let layout = Layout { per_receipt: args.per_receipt, group_by: args.group_by };
+    display_layout(&scan.receipts, layout, matcher.as_ref(), &aggregate_options, display_options)?;
+
+    if item_count > 0 {
+        info!(
+            "Currency detected for {} of {} items ({} assumed {})",
+            detected_currencies,
+            item_count,
+            item_count - detected_currencies,
+            args.currency_symbol
+        );
+    }
+    if currencies.len() > 1 {
+        let currencies: Vec<&str> = currencies.into_iter().collect();
+        info!("Receipts are priced in several currencies ({}); totals are kept separate", currencies.join(", "));
+    }
+    // Stdout only carries json or csv when they aren't written to a file
+    if human_output {
+        println!("Processed {}", scan.summary());
+    } else {
+        info!("Processed {}", scan.summary());
+    }
+
+    // On stderr, so the results stay machine-readable
+    if let Some(timings) = &scan_options.timings {
+        timings.display(scan_time);
+    }
+
+    if !human_output {
+        return Ok(exit_status(&scan));
+    }
 
-fn preprocess_image(img: DynamicImage) -> DynamicImage {
-    // Convert to grayscale
-    let gray = img.to_luma8();
+    display_tax_summary(&scan.receipts, display_options);
+    if let Some(categories) = &categories {
+        display_category_summary(&scan.receipts, categories, display_options);
+    }
+    if args.show_unmatched {
+        display_unmatched(&scan.receipts);
+    }
+
+    if spend_by_payment.keys().any(|(method, _)| method.is_some()) {
+        let spend: Vec<String> = spend_by_payment
+            .iter()
+            .map(|((method, currency), total)| {
+                let label = method.map_or("unknown", PaymentMethod::label);
+                format!("{} {}", label, display_options.format_amount(*total, currency))
+            })
+            .collect();
+        println!("Spent by payment method: {}", spend.join(", "));
+    }
+
+    if let Some(count) = args.highlight_receipts {
+        display_top_receipts(&scan.receipts, count, display_options);
+    }
+
+    if args.stats {
+        let aggregated = aggregate_products(products.clone(), matcher.as_ref(), &aggregate_options);
+        display_stats(&stats(&aggregated, &products), display_options);
+    }
 
-    // Increase contrast
-    let enhanced = enhance_contrast(gray);
+    if args.watch {
+        let db = conn.as_mut().zip(args.db.as_deref());
+        watch::watch_directory(&inputs, &mut scan, db, &parse_options, &scan_options, |receipts| {
+            display_layout(receipts, layout, matcher.as_ref(), &aggregate_options, display_options)
+        })?;
+    }
+
+    Ok(exit_status(&scan))
+}
+
+/// Locales whose skip keywords are used: --locale, or the languages in --lang that have keywords
+/// of their own
+fn locales(args: &Args) -> Vec<Locale> {
+    let requested: Vec<Locale> = if args.locale.is_empty() {
+        args.lang.split('+').filter_map(Locale::from_language).collect()
+    } else {
+        args.locale.iter().map(|&locale| locale.into()).collect()
+    };
+    let mut locales = Vec::new();
+    for locale in requested {
+        if !locales.contains(&locale) {
+            locales.push(locale);
+        }
+    }
+    locales
+}
 
-    DynamicImage::ImageLuma8(enhanced)
+/// Logs to stderr: errors only with --quiet, warnings by default, and notes on every processed
+/// image or also the OCR text with -v and -vv. RUST_LOG takes precedence
+fn init_logging(args: &Args) {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        // Only our own notes get more detailed with -v, dependencies stay at warnings
+        .filter_level(level.min(LevelFilter::Warn))
+        .filter_module("receipt_analyzer", level)
+        .format(|buf, record| match record.level() {
+            log::Level::Error => writeln!(buf, "error: {}", record.args()),
+            log::Level::Warn => writeln!(buf, "warning: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .parse_default_env()
+        .init();
 }
 
-fn enhance_contrast(img: ImageBuffer<Luma<u8>, Vec<u8>>) -> ImageBuffer<Luma<u8>, Vec<u8>> {
-    let mut enhanced = img.clone();
+/// Reports failed files and an empty result as errors, and exits with 1 if there were any, so
+/// scripts notice
+fn exit_status(scan: &DirectoryScan) -> ExitCode {
+    if scan.failed > 0 {
+        error!("{} of {} files failed", scan.failed, scan.succeeded + scan.failed);
+    }
+    let found_products = scan.receipts.iter().any(|receipt| !receipt.items.is_empty());
+    if !found_products {
+        error!("no products found");
+    }
 
-    for pixel in enhanced.pixels_mut() {
-        let value = pixel[0] as f32;
-        // Apply contrast enhancement
-        let new_value = ((value - 128.0) * 1.5 + 128.0).clamp(0.0, 255.0) as u8;
-        pixel[0] = new_value;
+    if scan.failed > 0 || !found_products {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
     }
+}
 
-    enhanced
+/// Stores receipts not recorded yet in the --db database
+fn record_in_db(conn: &mut rusqlite::Connection, db_path: &Path, receipts: &[Receipt]) -> Result<()> {
+    let counts = db::record_receipts(conn, receipts)?;
+    info!(
+        "Recorded {} new receipts in {} ({} already there)",
+        counts.added,
+        db_path.display(),
+        counts.known
+    );
+    Ok(())
 }
 
-fn parse_receipt_text(text: &str) -> Result<Vec<Product>> {
-    let mut products = Vec::new();
+/// Prints the resolved settings as TOML. Unset optional settings are listed as comments.
+fn print_config(args: &Args) {
+    fn enum_name<T: ValueEnum>(value: &T) -> String {
+        value.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()
+    }
+    fn optional(key: &str, value: Option<String>) -> String {
+        match value {
+            Some(value) => format!("{} = {}", key, value),
+            None => format!("# {} is unset", key),
+        }
+    }
+    fn paths(paths: &[PathBuf]) -> Vec<String> {
+        paths.iter().map(|path| path.display().to_string()).collect()
+    }
 
-    #[cfg(debug_assertions)]
-    println!("OCR Text:\n{}\n---", text); // Debug output
+    let skip_regex: Vec<String> = args.skip_regex.iter().map(|r| format!("{:?}", r.as_str())).collect();
+    let modified_since = args
+        .modified_since
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs().to_string());
 
-    // Enhanced patterns for multiple receipt formats
-    // Pattern 1: German format with quantity and total - "4x Löwenbräu Original a 3,00 12,00"
-    let pattern_qty_total = Regex::new(r"(\d+|[IilL])x?\s+([A-Za-zÄÖÜäöüß][A-Za-zÄÖÜäöüß0-9\s\-.]{2,40})\s+(?:a\s+)?(?:\d+[,.]\d{2}\s+)?(\d+[,.]\d{2})")?;
+    let lines = [
+        format!("input = {:?}", paths(&args.input)),
+        format!("recursive = {}", args.recursive),
+        format!("extensions = {:?}", args.extensions),
+        format!("matcher = {:?}", enum_name(&args.matcher)),
+        format!("rounding = {:?}", enum_name(&args.rounding)),
+        format!("currency_symbol = {:?}", args.currency_symbol),
+        format!("decimal_comma = {}", args.decimal_comma),
+        format!("show_net = {}", args.show_net),
+        format!("aggregate = {:?}", enum_name(&args.aggregate)),
+        format!("per_receipt = {}", args.per_receipt),
+        optional("group_by", args.group_by.map(|g| format!("{:?}", enum_name(&g)))),
+        format!("fuzzy_threshold = {}", args.fuzzy_threshold),
+        optional("top", args.top.map(|n| n.to_string())),
+        format!("no_color = {}", args.no_color),
+        optional("max_compare", args.max_compare.map(|n| n.to_string())),
+        format!("skip_regex = [{}]", skip_regex.join(", ")),
+        format!("skip_words = {:?}", paths(&args.skip_words)),
+        format!("replace_skip_words = {}", args.replace_skip_words),
+        format!("synonyms = {:?}", paths(&args.synonyms)),
+        format!("categories = {:?}", paths(&args.categories)),
+        format!("preserve_case = {}", args.preserve_case),
+        format!("explain_skips = {}", args.explain_skips),
+        format!("show_unmatched = {}", args.show_unmatched),
+        format!("credit_markers = {:?}", args.credit_markers),
+        format!("min_price = {:?}", args.min_price),
+        format!("max_price = {:?}", args.max_price),
+        format!("min_confidence = {:?}", args.min_confidence),
+        format!("min_name_len = {}", args.min_name_len),
+        format!("max_name_len = {}", args.max_name_len),
+        format!("total_tolerance = {:?}", args.total_tolerance),
+        optional("modified_since", modified_since),
+        format!("autocrop = {}", args.autocrop),
+        optional("target_dpi", args.target_dpi.map(|dpi| dpi.to_string())),
+        format!("deskew = {}", args.deskew),
+        format!("auto_rotate = {}", args.auto_rotate),
+        format!("binarize = {:?}", enum_name(&args.binarize)),
+        format!("window = {}", args.window),
+        format!("contrast = {:?}", args.contrast),
+        format!("gamma = {:?}", args.gamma),
+        format!("sharpen = {:?}", args.sharpen),
+        format!("retry_preprocess = {}", args.retry_preprocess),
+        optional("keep_processed", args.keep_processed.as_ref().map(|path| format!("{:?}", path.display().to_string()))),
+        optional("cache_dir", args.cache_dir.as_ref().map(|path| format!("{:?}", path.display().to_string()))),
+        format!("lang = {:?}", args.lang),
+        format!("locale = {:?}", args.locale.iter().map(enum_name).collect::<Vec<_>>()),
+        optional("tessdata_dir", args.tessdata_dir.as_ref().map(|path| format!("{:?}", path.display().to_string()))),
+        format!("psm = {}", args.psm),
+        format!("layout = {:?}", enum_name(&args.layout)),
+        optional("jobs", args.jobs.map(|n| n.to_string())),
+        optional("timeout", args.timeout.map(|secs| secs.to_string())),
+        optional("highlight_receipts", args.highlight_receipts.map(|n| n.to_string())),
+        format!("stats = {}", args.stats),
+        format!("timings = {}", args.timings),
+        format!("interactive = {}", args.interactive),
+        format!("strict = {}", args.strict),
+        format!("allow_duplicates = {}", args.allow_duplicates),
+        format!("quiet = {}", args.quiet),
+        format!("verbose = {}", args.verbose),
+        optional("format", args.format.map(|f| format!("{:?}", enum_name(&f)))),
+        optional("output", args.output.as_ref().map(|path| format!("{:?}", path.display().to_string()))),
+        optional("db", args.db.as_ref().map(|path| format!("{:?}", path.display().to_string()))),
+        format!("report = {}", args.report),
+        format!("watch = {}", args.watch),
+        format!("stdin = {}", args.stdin),
+        format!("dry_run = {}", args.dry_run),
+    ];
+    for line in lines {
+        println!("{}", line);
+    }
+}
 
-    // Pattern 2: Euro format - "1 CHICKEN HEALS €9.99" or "2° PIZZA €25.98"
-    let pattern_euro = Regex::new(r"(\d+)°?\s+([A-Z][A-Z0-9\s\-.]{2,30})\s+€(\d+[,.]?\d{2})")?;
+/// Extensions of the image and PDF files that are processed in a directory by default
+#[cfg(not(feature = "heic"))]
+const DEFAULT_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "tiff", "bmp", "webp", "pdf"];
+#[cfg(feature = "heic")]
+const DEFAULT_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "tiff", "bmp", "webp", "heic", "heif", "pdf"];
 
-    // Pattern 3: Simple product line - "EXTRA SPYCIES €0.00"
-    let pattern_euro_simple = Regex::new(r"([A-Z][A-Z0-9\s\-.]{2,30})\s+€(\d+[,.]?\d{2})")?;
+/// Source recorded for the receipt read with --stdin
+const STDIN_SOURCE: &str = "stdin";
 
-    // Pattern 4: German simple - "1 Cheeseburger* 1,19"
-    let pattern_de_simple = Regex::new(r"(\d+|[IilL])x?\s+([A-Za-zÄÖÜäöüß][A-Za-zÄÖÜäöüß0-9\s\-.*]{2,30})\s+(\d+[,.]\d{2})")?;
+/// Prefix of the preprocessed images handed to Tesseract, so --watch can tell them apart from receipts
+const TEMP_FILE_PREFIX: &str = "receipt-analyzer-";
 
-    // Pattern 5: Product name followed by price - fallback
-    let pattern_fallback = Regex::new(r"([A-Za-zÄÖÜäöüß][A-Za-zÄÖÜäöüß0-9\s\-.]{2,30})\s+(\d+[,.]\d{2})")?;
+/// OCRs the receipt files under `inputs` and reports them in directory order, one input after
+/// the other
+fn process_receipts(
+    inputs: &[PathBuf],
+    parse_options: &Arc<ParseOptions>,
+    scan_options: &Arc<ScanOptions>,
+) -> Result<DirectoryScan> {
+    let progress = &scan_options.progress;
+    progress.set_style(
+        ProgressStyle::with_template("{elapsed_precise} [{wide_bar}] {pos}/{len} files")?.progress_chars("=> "),
+    );
+    let reader = OcrReader { parse_options, scan_options, any_failed: AtomicBool::new(false) };
+    let scan = process_receipts_iter(inputs, &scan_options.files, &reader)?.try_fold(
+        DirectoryScan::default(),
+        |mut scan, result| {
+            // The progress bar keeps running while the first batches are reported
+            progress.suspend(|| scan.add_result(result, scan_options))?;
+            anyhow::Ok(scan)
+        },
+    )?;
+    progress.finish_and_clear();
+    Ok(scan)
+}
 
-    for line in text.lines() {
-        let line = line.trim();
-        if line.is_empty() || line.len() < 4 {
-            continue;
+/// Reads receipt files with Tesseract for `process_receipts_iter`, advancing the progress bar
+struct OcrReader<'a> {
+    parse_options: &'a Arc<ParseOptions>,
+    scan_options: &'a Arc<ScanOptions>,
+    /// With --strict, files not started yet are skipped once one has failed
+    any_failed: AtomicBool,
+}
+
+impl ReceiptReader for OcrReader<'_> {
+    fn started(&self, files: usize) {
+        self.scan_options.progress.set_length(files as u64);
+    }
+
+    fn read_file(&self, path: &Path) -> Vec<Result<Receipt>> {
+        if self.scan_options.strict && self.any_failed.load(Ordering::Relaxed) {
+            return Vec::new();
         }
+        let results = extract_receipts_from_file(path, self.parse_options, self.scan_options);
+        if results.iter().any(Result::is_err) {
+            self.any_failed.store(true, Ordering::Relaxed);
+        }
+        self.scan_options.progress.inc(1);
+        results
+    }
+}
 
-        // Skip headers, totals, taxes, etc.
-        if should_skip_line(line) {
-            continue;
+/// --stdin: OCRs the image piped in, reported like a directory with one file
+fn process_stdin(parse_options: &Arc<ParseOptions>, scan_options: &Arc<ScanOptions>) -> Result<DirectoryScan> {
+    let mut bytes = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut bytes).context("Failed to read the image from stdin")?;
+    if bytes.is_empty() {
+        anyhow::bail!("--stdin expects image data, but stdin is empty");
+    }
+    let img = image::load_from_memory(&bytes)
+        .context("The data on stdin is not an image in a supported format (JPEG, PNG, TIFF, BMP or WebP)")?;
+
+    let source = Path::new(STDIN_SOURCE);
+    let result = {
+        let (parse_options, scan_options) = (Arc::clone(parse_options), Arc::clone(scan_options));
+        with_timeout(scan_options.timeout, move || {
+            extract_receipt_from_image(img, source, &parse_options, &scan_options)
+        })
+    };
+    let mut scan = DirectoryScan::default();
+    scan.add_result(with_source(source, result), scan_options)?;
+    Ok(scan)
+}
+
+impl DirectoryScan {
+    /// "12 receipts (10 succeeded, 1 failed, 1 skipped as duplicates)" for the closing summary line
+    fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} receipts ({} succeeded, {} failed",
+            self.succeeded + self.failed + self.duplicates,
+            self.succeeded,
+            self.failed
+        );
+        if self.duplicates > 0 {
+            summary.push_str(&format!(", {} skipped as duplicates", self.duplicates));
         }
+        summary.push(')');
+        summary
+    }
 
-        // Try patterns in order of specificity
-        if let Some(captures) = pattern_qty_total.captures(line) {
-            if let (Some(qty_str), Some(name), Some(price_str)) =
-                (captures.get(1), captures.get(2), captures.get(3)) {
-                // Handle OCR errors: "Ix" -> "1"
-                parse_quantity(qty_str.as_str());
-                if let Ok(price) = parse_european_price(price_str.as_str()) {
-                    if price > 0.0 && price < 1000.0 {
-                        products.push(Product {
-                            name: clean_product_name(name.as_str()),
-                            price,
-                        });
-                    }
+    /// Reports the outcome of processing one image or PDF page and keeps the receipt, unless it
+    /// is a second scan of one kept before. A failure, which names its file, is counted, or
+    /// returned as the error with --strict
+    fn add_result(&mut self, result: Result<Receipt>, scan_options: &ScanOptions) -> Result<()> {
+        match result {
+            Ok(receipt) => {
+                let path = &receipt.source;
+                info!("Processing: {}", path.display());
+                if !scan_options.allow_duplicates
+                    && let Some(original) = self.receipts.iter().find(|kept| receipt.is_duplicate_of(kept)) {
+                    warn!(
+                        "skipping {}, it looks like a second scan of {} (--allow-duplicates keeps both)",
+                        path.display(),
+                        original.source.display()
+                    );
+                    self.duplicates += 1;
+                    return Ok(());
                 }
-            }
-        }
-        else if let Some(captures) = pattern_euro.captures(line) {
-            if let (Some(_qty_str), Some(name), Some(price_str)) =
-                (captures.get(1), captures.get(2), captures.get(3)) {
-                if let Ok(price) = parse_european_price(price_str.as_str()) {
-                    if price > 0.0 && price < 1000.0 {
-                        products.push(Product {
-                            name: clean_product_name(name.as_str()),
-                            price,
-                        });
-                    }
+                if let Some(store) = &receipt.store {
+                    info!("  Store: {}", store);
                 }
-            }
-        }
-        else if let Some(captures) = pattern_euro_simple.captures(line) {
-            if let (Some(name), Some(price_str)) = (captures.get(1), captures.get(2)) {
-                if let Ok(price) = parse_european_price(price_str.as_str()) {
-                    if price > 0.0 && price < 1000.0 {
-                        products.push(Product {
-                            name: clean_product_name(name.as_str()),
-                            price,
-                        });
-                    }
+                if let Some(date) = receipt.date {
+                    info!("  Date: {}", date);
                 }
-            }
-        }
-        else if let Some(captures) = pattern_de_simple.captures(line) {
-            if let (Some(_qty_str), Some(name), Some(price_str)) =
-                (captures.get(1), captures.get(2), captures.get(3)) {
-                if let Ok(price) = parse_european_price(price_str.as_str()) {
-                    if price > 0.0 && price < 1000.0 {
-                        products.push(Product {
-                            name: clean_product_name(name.as_str()),
-                            price,
-                        });
-                    }
+                if let Some(method) = receipt.payment {
+                    info!("  Paid by {}", method.label());
                 }
-            }
-        }
-        else if let Some(captures) = pattern_fallback.captures(line) {
-            if let (Some(name), Some(price_str)) = (captures.get(1), captures.get(2)) {
-                if let Ok(price) = parse_european_price(price_str.as_str()) {
-                    if price > 0.0 && price < 1000.0 {
-                        let name_str = name.as_str().trim();
-                        if name_str.len() > 2 && !name_str.chars().all(|c| c.is_numeric() || c == '.' || c == ',' || c == '-') {
-                            products.push(Product {
-                                name: clean_product_name(name_str),
-                                price,
-                            });
-                        }
+                // One receipt at a time, so the lines of parallel workers don't interleave
+                scan_options.progress.suspend(|| {
+                    for (reason, line) in &receipt.skipped {
+                        eprintln!("{}\t{}\t{}\t{}", receipt.source.display(), reason.category(), reason.rule(), line);
                     }
+                });
+                // A mismatch usually means OCR missed or misread an item
+                if let Some(printed_total) = receipt.printed_total
+                    && (receipt.item_total() - printed_total).abs() > scan_options.total_tolerance {
+                    warn!(
+                        "items on {} add up to {:.2} but the printed total is {:.2}",
+                        path.display(),
+                        receipt.item_total(),
+                        printed_total
+                    );
                 }
+                self.receipts.push(receipt);
+                self.succeeded += 1;
+            }
+            Err(e) if scan_options.strict => return Err(e),
+            Err(e) => {
+                error!("{:#}", e);
+                self.failed += 1;
             }
         }
+        Ok(())
     }
+}
 
-    Ok(products)
-}
-
-fn parse_quantity(qty_str: &str) -> u32 {
-    // Handle OCR errors where "1x" becomes "Ix", "lx", etc.
-    match qty_str.to_lowercase().as_str() {
-        "ix" | "lx" | "i" | "l" => 1,
-        _ => qty_str.parse().unwrap_or(1)
-    }
-}
-
-fn should_skip_line(line: &str) -> bool {
-    let line_lower = line.to_lowercase();
-    line_lower.contains("total") ||
-        line_lower.contains("subtotal") ||
-        line_lower.contains("summe") ||
-        line_lower.contains("netto") ||
-        line_lower.contains("brutto") ||
-        line_lower.contains("mwst") ||
-        line_lower.contains("tax") ||
-        line_lower.contains("steuer") ||
-        line_lower.contains("change") ||
-        line_lower.contains("wechselgeld") ||
-        line_lower.contains("receipt") ||
-        line_lower.contains("quittung") ||
-        line_lower.contains("rechnung") ||
-        line_lower.contains("datum") ||
-        line_lower.contains("date") ||
-        line_lower.contains("time") ||
-        line_lower.contains("uhrzeit") ||
-        line_lower.contains("tel:") ||
-        line_lower.contains("telefon") ||
-        line_lower.contains("adresse") ||
-        line_lower.contains("address") ||
-        line_lower.contains("vielen dank") ||
-        line_lower.contains("danke") ||
-        line_lower.contains("nr.") ||
-        line_lower.contains("nummer") ||
-        line_lower.contains("check:") ||
-        line_lower.contains("authorization") ||
-        line_lower.contains("approval") ||
-        line_lower.contains("payment") ||
-        line_lower.contains("card") ||
-        line_lower.contains("gratuity") ||
-        line_lower.contains("signature") ||
-        line_lower.contains("customer copy") ||
-        line_lower.contains("thanks") ||
-        line_lower.starts_with("#") ||
-        line_lower.starts_with("<<<") ||
-        line_lower.starts_with("888") ||
-        line_lower.contains("inkl") ||
-        line_lower.contains("gegeben") ||
-        line_lower.contains("euro0") ||
-        line_lower.contains("eur0") ||
-        line_lower.contains("cust:") ||
-        line_lower.contains("albany") ||
-        line_lower.contains("street") ||
-        line_lower.contains("nyc") ||
-        line_lower.contains("food club") ||
-        // Skip percentage lines
-        line_lower.contains("%") ||
-        // Skip lines that are just numbers
-        line.chars().all(|c| c.is_numeric() || c.is_whitespace())
-}
-
-fn parse_european_price(price_str: &str) -> Result<f64, std::num::ParseFloatError> {
-    // Handle both European (1,19) and US (1.19) decimal formats
-    if price_str.contains(',') {
-        // European format: replace comma with dot
-        price_str.replace(',', ".").parse::<f64>()
-    } else {
-        // US format: parse directly
-        price_str.parse::<f64>()
-    }
-}
-
-fn clean_product_name(name: &str) -> String {
-    name.trim()
-        .to_lowercase()
-        // Keep German umlauts and special characters
-        .chars()
-        .filter(|c| c.is_alphanumeric() || c.is_whitespace() || "äöüßÄÖÜ".contains(*c))
-        .collect::<String>()
-        .split_whitespace()
-        .collect::<Vec<_>>()
-        .join(" ")
-}
-
-fn aggregate_products(products: Vec<Product>) -> Vec<(String, f64)> {
-    let mut aggregated: HashMap<String, f64> = HashMap::new();
-    let matcher = SkimMatcherV2::default();
-
-    for product in products {
-        let mut found_match = false;
-        let mut best_match_key = String::new();
-        let mut best_score = 0;
-
-        // Try to find existing similar product name
-        for existing_key in aggregated.keys() {
-            if let Some(score) = matcher.fuzzy_match(existing_key, &product.name) {
-                if score > 80 && score > best_score { // Threshold for fuzzy matching
-                    best_score = score;
-                    best_match_key = existing_key.clone();
-                    found_match = true;
-                }
+/// Parses `--modified-since`: an existing file's modification time, Unix seconds, or a UTC
+/// date/time in the form YYYY-MM-DD[THH:MM[:SS]]
+fn parse_modified_since(value: &str) -> Result<SystemTime, String> {
+    if let Ok(metadata) = std::fs::metadata(value) {
+        return metadata
+            .modified()
+            .map_err(|e| format!("cannot read modification time of '{}': {}", value, e));
+    }
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(UNIX_EPOCH + Duration::from_secs(seconds));
+    }
+
+    let pattern = Regex::new(r"^(\d{4})-(\d{2})-(\d{2})(?:[T ](\d{2}):(\d{2})(?::(\d{2}))?)?$")
+        .map_err(|e| e.to_string())?;
+    let captures = pattern.captures(value).ok_or_else(|| {
+        format!("expected a file path, Unix seconds or YYYY-MM-DD[THH:MM[:SS]], got '{}'", value)
+    })?;
+    let field = |i: usize| captures.get(i).map_or(0, |m| m.as_str().parse::<i64>().unwrap_or(0));
+    let (year, month, day) = (field(1), field(2), field(3));
+    let (hour, minute, second) = (field(4), field(5), field(6));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return Err(format!("'{}' is not a valid date/time", value));
+    }
+
+    let seconds = days_from_civil(year, month, day) * 86_400 + hour * 3_600 + minute * 60 + second;
+    let seconds = u64::try_from(seconds).map_err(|_| format!("'{}' is before 1970", value))?;
+    Ok(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Days since 1970-01-01 for a Gregorian calendar date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Count years from March so the leap day falls at the end of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// OCRs an image file, every page of a PDF or every image in a ZIP archive. Pages are returned
+/// as separate receipts, labelled "file.pdf#page=N" when there is more than one; failures name
+/// the file or page
+fn extract_receipts_from_file(
+    path: &Path,
+    parse_options: &Arc<ParseOptions>,
+    scan_options: &Arc<ScanOptions>,
+) -> Vec<Result<Receipt>> {
+    read_file_sources(path, parse_options, scan_options)
+        .into_iter()
+        .map(|(source, result)| with_source(&source, result))
+        .collect()
+}
+
+/// Adds the receipt's source to a failure: "Failed to process scan.pdf#page=2"
+fn with_source(source: &Path, result: Result<Receipt>) -> Result<Receipt> {
+    result.with_context(|| format!("Failed to process {}", source.display()))
+}
+
+/// `extract_receipts_from_file` with the source of every result
+fn read_file_sources(
+    path: &Path,
+    parse_options: &Arc<ParseOptions>,
+    scan_options: &Arc<ScanOptions>,
+) -> Vec<(PathBuf, Result<Receipt>)> {
+    let has_extension = |expected: &str| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case(expected));
+    if has_extension("zip") {
+        return extract_receipts_from_zip(path, parse_options, scan_options);
+    }
+    if !has_extension("pdf") {
+        let (source, parse_options, scan_options) =
+            (path.to_path_buf(), Arc::clone(parse_options), Arc::clone(scan_options));
+        let result = with_timeout(scan_options.timeout, move || {
+            open_image(&source).and_then(|img| extract_receipt_from_image(img, &source, &parse_options, &scan_options))
+        });
+        return vec![(path.to_path_buf(), result)];
+    }
+
+    let pages = match rasterize_pdf(path) {
+        Ok(pages) => pages,
+        Err(e) => return vec![(path.to_path_buf(), Err(e))],
+    };
+    let page_count = pages.len();
+    pages
+        .into_iter()
+        .enumerate()
+        .map(|(index, page)| {
+            let label = if page_count > 1 {
+                PathBuf::from(format!("{}#page={}", path.display(), index + 1))
+            } else {
+                path.to_path_buf()
+            };
+            let (source, parse_options, scan_options) =
+                (label.clone(), Arc::clone(parse_options), Arc::clone(scan_options));
+            let receipt = with_timeout(scan_options.timeout, move || {
+                extract_receipt_from_image(page, &source, &parse_options, &scan_options)
+            });
+            (label, receipt)
+        })
+        .collect()
+}
+
+/// OCRs the images in a ZIP archive, subdirectories included, decoding them from memory. Every
+/// image is a receipt of its own, labelled "backup.zip#receipts/IMG_01.jpg"; other entries are
+/// skipped
+fn extract_receipts_from_zip(
+    path: &Path,
+    parse_options: &Arc<ParseOptions>,
+    scan_options: &Arc<ScanOptions>,
+) -> Vec<(PathBuf, Result<Receipt>)> {
+    let archive = File::open(path)
+        .context("Failed to open ZIP archive")
+        .and_then(|file| zip::ZipArchive::new(file).context("Failed to read ZIP archive"));
+    let mut archive = match archive {
+        Ok(archive) => archive,
+        Err(e) => return vec![(path.to_path_buf(), Err(e))],
+    };
+
+    let mut results = Vec::new();
+    for index in 0..archive.len() {
+        let mut entry = match archive.by_index(index).with_context(|| format!("Failed to read entry {}", index + 1)) {
+            Ok(entry) => entry,
+            Err(e) => {
+                results.push((path.to_path_buf(), Err(e)));
+                continue;
             }
+        };
+        if !entry.is_file() || !is_zipped_image(entry.name()) {
+            continue;
         }
-
-        if found_match {
-            *aggregated.get_mut(&best_match_key).unwrap() += product.price;
-        } else {
-            aggregated.insert(product.name, product.price);
+        let label = PathBuf::from(format!("{}#{}", path.display(), entry.name()));
+        let mut bytes = Vec::new();
+        if let Err(e) = entry.read_to_end(&mut bytes) {
+            results.push((label, Err(anyhow::Error::from(e).context("Failed to extract from ZIP archive"))));
+            continue;
         }
+
+        let (source, parse_options, scan_options) =
+            (label.clone(), Arc::clone(parse_options), Arc::clone(scan_options));
+        let receipt = with_timeout(scan_options.timeout, move || {
+            let img = image::load_from_memory(&bytes).context("Failed to decode image")?;
+            extract_receipt_from_image(img, &source, &parse_options, &scan_options)
+        });
+        results.push((label, receipt));
+    }
+    results
+}
+
+/// ZIP entries read as receipts: images in a format `image` can decode, except hidden files and
+/// the resource forks macOS adds under "__MACOSX/"
+fn is_zipped_image(name: &str) -> bool {
+    let path = Path::new(name);
+    let hidden = path.file_name().and_then(|name| name.to_str()).is_none_or(|name| name.starts_with('.'));
+    !hidden
+        && !name.starts_with("__MACOSX/")
+        && ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled())
+}
+
+/// Runs `work` on a thread of its own and gives up waiting after `timeout`. An abandoned thread
+/// can't be stopped, it runs until Tesseract returns or the process exits
+fn with_timeout<T: Send + 'static>(
+    timeout: Option<Duration>,
+    work: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let Some(timeout) = timeout else {
+        return work();
+    };
+    let (sender, receiver) = mpsc::channel();
+    std::thread::Builder::new()
+        .spawn(move || sender.send(work()))
+        .context("Failed to start a worker thread")?;
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => anyhow::bail!("gave up after {} seconds (--timeout)", timeout.as_secs()),
+        Err(RecvTimeoutError::Disconnected) => anyhow::bail!("the worker thread panicked"),
     }
+}
 
-    // Sort by price descending
-    let mut sorted: Vec<_> = aggregated.into_iter().collect();
-    sorted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+/// Tesseract languages: German receipts, with English for imported products and card terminals
+const DEFAULT_OCR_LANGUAGES: &str = "deu+eng";
 
-    sorted
+/// Where Tesseract packages install their language data, checked when neither --tessdata-dir
+/// nor TESSDATA_PREFIX is set
+const TESSDATA_LOCATIONS: [&str; 6] = [
+    "/usr/share/tesseract-ocr/5/tessdata",
+    "/usr/share/tesseract-ocr/4.00/tessdata",
+    "/usr/share/tessdata",
+    "/usr/local/share/tessdata",
+    "/opt/homebrew/share/tessdata",
+    "C:\\Program Files\\Tesseract-OCR\\tessdata",
+];
+
+/// Page segmentation mode 4: a single column of text of variable sizes, the layout of a receipt
+const DEFAULT_PSM: u8 = 4;
+
+/// Starts Tesseract with the configured language data and page segmentation mode
+fn new_tesseract(ocr: &OcrOptions) -> Result<Tesseract> {
+    let tessdata_dir = match &ocr.tessdata_dir {
+        Some(dir) => Some(dir.to_str().context("--tessdata-dir is not valid UTF-8")?),
+        None => None,
+    };
+    let tesseract = Tesseract::new(tessdata_dir, Some(&ocr.languages)).with_context(|| {
+        let location = match &ocr.tessdata_dir {
+            Some(dir) => format!("in {}", dir.display()),
+            None => "in the default location; pass --tessdata-dir or set TESSDATA_PREFIX".to_string(),
+        };
+        format!("Failed to load the Tesseract language data for {} {}", ocr.languages, location)
+    })?;
+    tesseract
+        .set_variable("tessedit_pageseg_mode", &ocr.psm.to_string())
+        .with_context(|| format!("Failed to set page segmentation mode {}", ocr.psm))
 }
 
-fn display_results(products: Vec<(String, f64)>) {
-    if products.is_empty() {
-        println!("No products found in receipt images.");
-        return;
+/// Checks that every --lang language has a traineddata file, listing the installed ones if not.
+/// When the data directory can't be located the check is left to Tesseract itself
+fn check_language_data(ocr: &OcrOptions) -> Result<()> {
+    let mut languages: Vec<&str> = ocr.languages.split('+').collect();
+    if languages.iter().any(|lang| lang.trim().is_empty()) {
+        anyhow::bail!("--lang expects language codes joined with \"+\", like deu+eng, got \"{}\"", ocr.languages);
+    }
+    if ocr.auto_rotate {
+        languages.push("osd");
     }
 
-    let mut table = Table::new();
-    table.set_format(*format::consts::FORMAT_BORDERS_ONLY);
-    table.set_titles(Row::new(vec![
-        Cell::new("Product Name"),
-        Cell::new("Total Price"),
-    ]));
+    let Some(dir) = tessdata_dir(ocr) else {
+        return Ok(());
+    };
+    let missing: Vec<&str> = languages
+        .into_iter()
+        .filter(|lang| !dir.join(format!("{}.traineddata", lang)).is_file())
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
 
-    let mut grand_total = 0.0;
+    let mut installed: Vec<String> = std::fs::read_dir(&dir)
+        .with_context(|| format!("Failed to list the language data in {}", dir.display()))?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_suffix(".traineddata").map(str::to_string)
+        })
+        .collect();
+    installed.sort();
+    let installed = if installed.is_empty() { "none".to_string() } else { installed.join(", ") };
+    anyhow::bail!(
+        "No Tesseract language data for {} in {} (installed: {}). Install the language package, \
+         e.g. tesseract-ocr-{}, or point --tessdata-dir at a directory containing {}.traineddata",
+        missing.join(", "),
+        dir.display(),
+        installed,
+        missing[0],
+        missing[0]
+    )
+}
+
+/// The directory Tesseract loads language data from: --tessdata-dir, else TESSDATA_PREFIX,
+/// else the first standard install location that exists
+fn tessdata_dir(ocr: &OcrOptions) -> Option<PathBuf> {
+    if let Some(dir) = &ocr.tessdata_dir {
+        return Some(dir.clone());
+    }
+    if let Some(prefix) = std::env::var_os("TESSDATA_PREFIX").filter(|prefix| !prefix.is_empty()) {
+        // Tesseract 4 and later take the tessdata directory itself, 3.x its parent
+        let prefix = PathBuf::from(prefix);
+        let nested = prefix.join("tessdata");
+        return Some(if nested.is_dir() { nested } else { prefix });
+    }
+    TESSDATA_LOCATIONS.iter().map(PathBuf::from).find(|dir| dir.is_dir())
+}
 
-    for (name, price) in &products {
-        table.add_row(Row::new(vec![
-            Cell::new(name),
-            Cell::new(&format!("{:.2}€", price)),
-        ]));
-        grand_total += price;
+/// Decodes an image file. HEIC/HEIF photos go through libheif, everything else through `image`
+fn open_image(path: &Path) -> Result<DynamicImage> {
+    let is_heic = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("heic") || ext.eq_ignore_ascii_case("heif"));
+    if is_heic {
+        return decode_heic(path);
     }
+    Ok(image::open(path)?)
+}
+
+#[cfg(feature = "heic")]
+fn decode_heic(path: &Path) -> Result<DynamicImage> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let path = path.to_str().context("HEIC file path is not valid UTF-8")?;
+    let context = HeifContext::read_from_file(path).context("Failed to read HEIC file")?;
+    let handle = context.primary_image_handle()?;
+    let decoded = LibHeif::new().decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)?;
+    let plane = decoded.planes().interleaved.context("HEIC image has no RGB plane")?;
+
+    // Rows may be padded beyond width * 3 bytes
+    let row_len = plane.width as usize * 3;
+    let pixels: Vec<u8> = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_len])
+        .copied()
+        .collect();
+    let image = image::RgbImage::from_raw(plane.width, plane.height, pixels)
+        .context("HEIC image data is shorter than its size")?;
+    Ok(DynamicImage::ImageRgb8(image))
+}
+
+#[cfg(not(feature = "heic"))]
+fn decode_heic(_path: &Path) -> Result<DynamicImage> {
+    anyhow::bail!("HEIC support is not built in, rebuild with `--features heic`")
+}
+
+/// Resolution PDF pages are rendered at, a good size for Tesseract
+const PDF_RENDER_DPI: f32 = 300.0;
+
+/// Renders every page of a PDF to an image. Encrypted PDFs are reported as errors.
+fn rasterize_pdf(path: &Path) -> Result<Vec<DynamicImage>> {
+    let bindings = Pdfium::bind_to_system_library().context("Failed to load the Pdfium library")?;
+    let pdfium = Pdfium::new(bindings);
+    let document = pdfium.load_pdf_from_file(path, None).map_err(|e| match e {
+        PdfiumError::PdfiumLibraryInternalError(PdfiumInternalError::PasswordError) => {
+            anyhow::anyhow!("PDF is encrypted")
+        }
+        e => anyhow::Error::from(e).context("Failed to open PDF"),
+    })?;
+
+    // PDF pages are measured in points, 72 per inch
+    let config = PdfRenderConfig::new().scale_page_by_factor(PDF_RENDER_DPI / 72.0);
+    document
+        .pages()
+        .iter()
+        .map(|page| Ok(page.render_with_config(&config)?.as_image()))
+        .collect()
+}
+
+/// OCRs one image; `source` is recorded on the receipt
+fn extract_receipt_from_image(
+    img: DynamicImage,
+    source: &Path,
+    parse_options: &ParseOptions,
+    scan_options: &ScanOptions,
+) -> Result<Receipt> {
+    timings::time(scan_options.timings.as_ref(), source, Stage::Total, || {
+        let img = if scan_options.ocr.auto_rotate { rotate_upright(img, source, scan_options)? } else { img };
+
+        // Faded thermal paper often comes out blank with the contrast boost but readable in pure
+        // black and white. The original is only kept around when a retry can happen
+        let retry_img = (scan_options.retry_preprocess && scan_options.preprocess.binarization != Binarization::Otsu)
+            .then(|| img.clone());
+        let receipt = ocr_receipt(img, &scan_options.preprocess, source, parse_options, scan_options)?;
+        let Some(img) = retry_img.filter(|_| receipt.items.is_empty()) else {
+            return Ok(receipt);
+        };
+
+        let otsu = PreprocessOptions { binarization: Binarization::Otsu, ..scan_options.preprocess };
+        let retried = ocr_receipt(img, &otsu, source, parse_options, scan_options)?;
+        if retried.items.is_empty() {
+            scan_options
+                .progress
+                .suspend(|| info!("{}: no products with either preprocessing pass", source.display()));
+            Ok(receipt)
+        } else {
+            scan_options.progress.suspend(|| {
+                info!(
+                    "{}: no products with the first preprocessing pass, {} found after retrying with Otsu binarization",
+                    source.display(),
+                    retried.items.len()
+                )
+            });
+            Ok(retried)
+        }
+    })
+}
+
+/// --auto-rotate: turns `img` upright, unless Tesseract isn't confident about its orientation
+fn rotate_upright(img: DynamicImage, source: &Path, scan_options: &ScanOptions) -> Result<DynamicImage> {
+    let Some(orientation) = osd::detect(&img, &scan_options.ocr)? else {
+        scan_options
+            .progress
+            .suspend(|| debug!("{}: too little text to detect the orientation", source.display()));
+        return Ok(img);
+    };
+    if orientation.confidence < osd::MIN_CONFIDENCE {
+        scan_options.progress.suspend(|| {
+            debug!(
+                "{}: not rotating by {}°, orientation confidence {:.2} is too low",
+                source.display(),
+                orientation.rotate,
+                orientation.confidence
+            )
+        });
+        return Ok(img);
+    }
+    if orientation.rotate != 0 {
+        scan_options.progress.suspend(|| {
+            info!(
+                "{}: rotated by {}° (orientation confidence {:.2})",
+                source.display(),
+                orientation.rotate,
+                orientation.confidence
+            )
+        });
+    }
+    Ok(osd::rotate(img, orientation.rotate))
+}
+
+/// One OCR pass: preprocesses `img` with `preprocess`, runs Tesseract and parses the lines.
+/// With --cache-dir, an image read before with the same settings skips both preprocessing and OCR
+fn ocr_receipt(
+    img: DynamicImage,
+    preprocess: &PreprocessOptions,
+    source: &Path,
+    parse_options: &ParseOptions,
+    scan_options: &ScanOptions,
+) -> Result<Receipt> {
+    let tsv = match &scan_options.cache_dir {
+        Some(dir) => {
+            let key = cache::key(&img, preprocess, &scan_options.ocr);
+            match cache::get(dir, &key) {
+                Some(tsv) => tsv,
+                None => {
+                    let tsv = run_tesseract(img, preprocess, source, scan_options)?;
+                    // A cache that can't be written only costs time on the next run
+                    if let Err(e) = cache::put(dir, &key, &tsv) {
+                        scan_options.progress.suspend(|| warn!("{:#}", e));
+                    }
+                    tsv
+                }
+            }
+        }
+        None => run_tesseract(img, preprocess, source, scan_options)?,
+    };
+    let lines = match scan_options.ocr.layout {
+        LayoutMode::Regex => parse_tesseract_tsv(&tsv),
+        LayoutMode::Spatial => parse_tesseract_tsv_spatial(&tsv)?,
+    };
+
+    // One record per image, so the lines of parallel workers don't interleave
+    if log::log_enabled!(log::Level::Debug) {
+        let text: Vec<String> = lines
+            .iter()
+            .map(|line| format!("[{:3.0}] {}", line.confidence, line.text))
+            .collect();
+        scan_options
+            .progress
+            .suspend(|| debug!("OCR text of {}:\n{}\n---", source.display(), text.join("\n")));
+    }
+
+    let receipt =
+        timings::time(scan_options.timings.as_ref(), source, Stage::Parse, || parse_receipt(&lines, parse_options))?;
+    Ok(Receipt { source: source.to_path_buf(), ..receipt })
+}
+
+/// Preprocesses `img` and returns Tesseract's TSV output for it
+fn run_tesseract(
+    img: DynamicImage,
+    preprocess: &PreprocessOptions,
+    source: &Path,
+    scan_options: &ScanOptions,
+) -> Result<String> {
+    let timings = scan_options.timings.as_ref();
+    // Preprocess image for better OCR
+    let processed_img =
+        timings::time(timings, source, Stage::Preprocess, || preprocess_image_with_options(img, preprocess));
+    timings::time(timings, source, Stage::Ocr, || save_and_read(&processed_img, source, scan_options))
+}
+
+/// Saves the preprocessed image for Tesseract and returns its TSV output
+fn save_and_read(processed_img: &DynamicImage, source: &Path, scan_options: &ScanOptions) -> Result<String> {
+    // Save processed image, always as PNG so Tesseract never has to guess the format from a
+    // misleading source extension. Without --keep-processed the file gets a unique name in the
+    // run's temp dir and is deleted when `temp_file` is dropped, also on early returns
+    let mut temp_file = None;
+    let image_path = match &scan_options.keep_processed {
+        Some(dir) => {
+            let path = dir.join(processed_image_name(source));
+            processed_img
+                .save_with_format(&path, ImageFormat::Png)
+                .with_context(|| format!("Failed to save the preprocessed image to {}", path.display()))?;
+            path
+        }
+        None => {
+            let file = temp_file.insert(
+                tempfile::Builder::new()
+                    .prefix(TEMP_FILE_PREFIX)
+                    .suffix(".png")
+                    .tempfile_in(&scan_options.temp_dir)
+                    .context("Failed to create temporary image file")?,
+            );
+            processed_img.write_to(file, ImageFormat::Png)?;
+            file.flush()?;
+            file.path().to_path_buf()
+        }
+    };
+    let image_path = image_path.to_str().context("Preprocessed image path is not valid UTF-8")?;
 
-    table.add_row(Row::new(vec![
-        Cell::new("TOTAL"),
-        Cell::new(&format!("{:.2}€", grand_total)).style_spec("b"),
-    ]));
+    let mut tesseract = new_tesseract(&scan_options.ocr)?.set_image(image_path)?;
 
-    table.printstd();
-    println!("\nFound {} unique products", products.len());
+    // TSV output carries a confidence and a bounding box for every word, which plain text doesn't
+    Ok(tesseract.get_tsv_text(0)?)
 }
@@ -0,0 +1,84 @@
+//! Page orientation detection for `--auto-rotate`, using Tesseract's orientation and script
+//! detection (OSD). The tesseract crate doesn't wrap it, so this goes through tesseract-sys.
+
+use crate::OcrOptions;
+use anyhow::{Context, Result};
+use image::DynamicImage;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use tesseract_sys::{
+    TessBaseAPI, TessBaseAPICreate, TessBaseAPIDelete, TessBaseAPIDetectOrientationScript, TessBaseAPIInit3,
+    TessBaseAPISetImage,
+};
+
+/// Below this orientation confidence the image is left as it is. Tesseract reports around 1 for
+/// photos without much text, where its guess is often wrong
+pub const MIN_CONFIDENCE: f32 = 2.0;
+
+/// How an image has to be turned to be upright
+#[derive(Clone, Copy, Debug)]
+pub struct Orientation {
+    /// Clockwise rotation in degrees: 0, 90, 180 or 270
+    pub rotate: u32,
+    pub confidence: f32,
+}
+
+/// Deletes the Tesseract handle on every return path
+struct Handle(*mut TessBaseAPI);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe { TessBaseAPIDelete(self.0) }
+    }
+}
+
+/// Detects the orientation of `img` with the osd language data. `None` if Tesseract finds too
+/// little text to tell
+pub fn detect(img: &DynamicImage, ocr: &OcrOptions) -> Result<Option<Orientation>> {
+    let gray = img.to_luma8();
+    let (width, height) = (c_int::try_from(gray.width())?, c_int::try_from(gray.height())?);
+    let tessdata_dir = match &ocr.tessdata_dir {
+        Some(dir) => Some(CString::new(dir.to_str().context("--tessdata-dir is not valid UTF-8")?)?),
+        None => None,
+    };
+    let language = CString::new("osd")?;
+
+    let handle = Handle(unsafe { TessBaseAPICreate() });
+    let datapath = tessdata_dir.as_ref().map_or(ptr::null(), |dir| dir.as_ptr());
+    if unsafe { TessBaseAPIInit3(handle.0, datapath, language.as_ptr()) } != 0 {
+        anyhow::bail!("Failed to load the Tesseract orientation data (osd.traineddata) needed by --auto-rotate");
+    }
+    // Tesseract copies the pixels, `gray` only has to outlive this call
+    unsafe { TessBaseAPISetImage(handle.0, gray.as_ptr(), width, height, 1, width) };
+
+    let mut orient_deg: c_int = 0;
+    let mut orient_conf: f32 = 0.0;
+    let mut script_name: *const c_char = ptr::null();
+    let mut script_conf: f32 = 0.0;
+    let detected = unsafe {
+        TessBaseAPIDetectOrientationScript(
+            handle.0,
+            &mut orient_deg,
+            &mut orient_conf,
+            &mut script_name,
+            &mut script_conf,
+        )
+    };
+    if detected == 0 {
+        return Ok(None);
+    }
+
+    // The same conversion as the "Rotate:" line of `tesseract --psm 0`
+    Ok(Some(Orientation { rotate: (360 - orient_deg.rem_euclid(360) as u32) % 360, confidence: orient_conf }))
+}
+
+/// `img` turned clockwise by `degrees`, a multiple of 90
+pub fn rotate(img: DynamicImage, degrees: u32) -> DynamicImage {
+    match degrees {
+        90 => img.rotate90(),
+        180 => img.rotate180(),
+        270 => img.rotate270(),
+        _ => img,
+    }
+}
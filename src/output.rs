@@ -0,0 +1,999 @@
+//! Printing the results: tables on stdout, or JSON, CSV and HTML on stdout or in the --output file.
+
+use crate::db;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use prettytable::{format, Cell, Row, Table};
+use receipt_analyzer::{
+    aggregate_products, category_totals, count_identical_items, round_price, serialize_cents, serialize_cents_map,
+    serialize_optional_cents, stats, vat_rate, AggregateOptions, AggregatedProduct, Categories, NameMatcher, Product,
+    Receipt, Rounding, Stats, DEFAULT_CURRENCY, NO_DATE, UNKNOWN_STORE,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum GroupBy {
+    /// German VAT class printed next to the price (A = 19%, B = 7%)
+    Vat,
+    /// Store named in the receipt header ("REWE", "ALDI", ...)
+    Store,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable table (default)
+    #[default]
+    Table,
+    /// {"products": [...], "grand_total": {...}} on stdout
+    Json,
+    /// One row per product with a header line
+    Csv,
+    /// Self-contained HTML page with styled tables, for sharing
+    Html,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Aggregation {
+    /// Total spent per product, with units and average unit price when some product has several
+    #[default]
+    Sum,
+    /// How often each product appears on the receipts, next to the units bought
+    Count,
+    /// Mean price of one unit
+    Avg,
+    /// Lowest and highest unit price paid
+    Minmax,
+}
+
+/// How many receipts a run read, for the JSON `summary`
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct RunSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Second scans of receipts read before, left out of the results
+    pub duplicates: usize,
+}
+
+/// One receipt of the --highlight-receipts ranking
+#[derive(Serialize)]
+struct ReceiptSummary<'a> {
+    #[serde(rename = "source")]
+    path: &'a Path,
+    store: Option<&'a str>,
+    date: Option<String>,
+    #[serde(rename = "items")]
+    item_count: usize,
+    currency: &'static str,
+    #[serde(serialize_with = "serialize_cents")]
+    total: f64,
+}
+
+/// Settings that control how results are printed
+#[derive(Clone, Copy, Default)]
+pub struct DisplayOptions<'a> {
+    pub format: OutputFormat,
+    /// File for json, csv and html results, stdout if `None`
+    pub output: Option<&'a Path>,
+    /// Directories and files the receipts were read from, named in the HTML header
+    pub inputs: &'a [PathBuf],
+    pub rounding: Rounding,
+    /// Add net price and VAT columns
+    pub show_net: bool,
+    /// Statistic listed per product before its total
+    pub aggregation: Aggregation,
+    /// Only list this many products, all if `None`
+    pub top: Option<usize>,
+    /// Color negative amounts and the grand total
+    pub color: bool,
+    /// Printed after amounts in the default currency, which items without a detected currency
+    /// are counted in
+    pub currency_symbol: &'a str,
+    /// Print amounts as "3,50€" instead of "3.50€"
+    pub decimal_comma: bool,
+    /// Add the --stats numbers to JSON reports
+    pub stats: bool,
+    /// Receipts read by the run, reported in JSON
+    pub summary: RunSummary,
+    /// Add the N receipts with the highest totals to JSON reports
+    pub highlight_receipts: Option<usize>,
+}
+
+impl DisplayOptions<'_> {
+    /// `value` rounded to cents and followed by the symbol of `currency`: "3.50€", "3,50$"
+    pub fn format_amount(&self, value: f64, currency: &str) -> String {
+        let amount = format!("{:.2}", round_price(value, self.rounding));
+        let amount = if self.decimal_comma { amount.replace('.', ",") } else { amount };
+        let symbol = if currency == DEFAULT_CURRENCY { self.currency_symbol } else { currency_symbol(currency) };
+        format!("{}{}", amount, symbol)
+    }
+}
+
+/// How the products are arranged in tables
+#[derive(Clone, Copy)]
+pub struct Layout {
+    pub per_receipt: bool,
+    pub group_by: Option<GroupBy>,
+}
+
+/// Prints the products of `receipts` as `layout` asks
+pub fn display_layout(
+    receipts: &[Receipt],
+    layout: Layout,
+    matcher: &dyn NameMatcher,
+    aggregate_options: &AggregateOptions,
+    display_options: DisplayOptions<'_>,
+) -> Result<()> {
+    // Both are one document for the whole run
+    match display_options.format {
+        OutputFormat::Html => {
+            return write_html(receipts, layout.per_receipt, matcher, aggregate_options, display_options);
+        }
+        OutputFormat::Json => {
+            return write_json(receipts, layout.per_receipt, matcher, aggregate_options, display_options);
+        }
+        OutputFormat::Table | OutputFormat::Csv => {}
+    }
+    if layout.per_receipt {
+        return display_receipts(receipts, display_options);
+    }
+
+    let products: Vec<Product> = receipts.iter().flat_map(|receipt| receipt.items.iter().cloned()).collect();
+    match layout.group_by {
+        Some(GroupBy::Vat) => display_vat_groups(products, matcher, aggregate_options, display_options),
+        Some(GroupBy::Store) => display_store_groups(products, matcher, aggregate_options, display_options),
+        None => display_results(aggregate_products(products, matcher, aggregate_options), display_options),
+    }
+}
+
+/// `paths` separated by commas, for naming the inputs of a run
+pub fn join_paths(paths: &[PathBuf]) -> String {
+    paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// The --format given, else the one matching the --output extension, else a table
+pub fn resolve_output_format(format: Option<OutputFormat>, output: Option<&Path>) -> Result<OutputFormat> {
+    let Some(output) = output else {
+        return Ok(format.unwrap_or(OutputFormat::Table));
+    };
+    let format = match format {
+        Some(format) => format,
+        None => match output.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase).as_deref() {
+            Some("csv") => OutputFormat::Csv,
+            Some("json") => OutputFormat::Json,
+            Some("html" | "htm") => OutputFormat::Html,
+            _ => anyhow::bail!(
+                "Cannot tell the output format from {}, pass --format csv, json or html",
+                output.display()
+            ),
+        },
+    };
+    if format == OutputFormat::Table {
+        anyhow::bail!("--output writes csv, json or html, not a table");
+    }
+    Ok(format)
+}
+
+fn display_vat_groups(
+    products: Vec<Product>,
+    matcher: &dyn NameMatcher,
+    aggregate_options: &AggregateOptions,
+    display_options: DisplayOptions<'_>,
+) -> Result<()> {
+    let mut groups: BTreeMap<Option<char>, Vec<Product>> = BTreeMap::new();
+    for product in products {
+        groups.entry(product.vat_class).or_default().push(product);
+    }
+
+    let mut grand_totals: BTreeMap<&str, f64> = BTreeMap::new();
+    // Known classes first, "unknown" last
+    let unknown = groups.remove(&None);
+    for (class, group) in groups.into_iter().chain(unknown.map(|group| (None, group))) {
+        match class.and_then(|c| vat_rate(c).map(|rate| (c, rate))) {
+            Some((c, rate)) => println!("\nVAT class {} ({}%)", c, rate),
+            None => println!("\nVAT class unknown"),
+        }
+        add_currency_totals(&mut grand_totals, &group);
+        display_results(aggregate_products(group, matcher, aggregate_options), display_options)?;
+    }
+
+    print_grand_totals(&grand_totals, display_options);
+    Ok(())
+}
+
+/// Prints one table per store, each with its own subtotal, followed by the grand total
+fn display_store_groups(
+    products: Vec<Product>,
+    matcher: &dyn NameMatcher,
+    aggregate_options: &AggregateOptions,
+    display_options: DisplayOptions<'_>,
+) -> Result<()> {
+    let mut groups: BTreeMap<Option<String>, Vec<Product>> = BTreeMap::new();
+    for product in products {
+        groups.entry(product.store.clone()).or_default().push(product);
+    }
+
+    let mut grand_totals: BTreeMap<&str, f64> = BTreeMap::new();
+    // Known stores first, "unknown" last
+    let unknown = groups.remove(&None);
+    for (store, group) in groups.into_iter().chain(unknown.map(|group| (None, group))) {
+        println!("\n{}", store.as_deref().unwrap_or(UNKNOWN_STORE));
+        add_currency_totals(&mut grand_totals, &group);
+        display_results(aggregate_products(group, matcher, aggregate_options), display_options)?;
+    }
+
+    print_grand_totals(&grand_totals, display_options);
+    Ok(())
+}
+
+/// Prints one table per receipt, each with its own subtotal, followed by the grand total.
+/// Items aren't fuzzy-merged here: repeated scans of the same article are counted instead ("apfel ×2")
+fn display_receipts(receipts: &[Receipt], display_options: DisplayOptions<'_>) -> Result<()> {
+    let mut grand_totals: BTreeMap<&str, f64> = BTreeMap::new();
+    for receipt in receipts {
+        println!("\n{}", receipt.heading());
+        add_currency_totals(&mut grand_totals, &receipt.items);
+        display_results(counted_items(receipt), display_options)?;
+    }
+
+    print_grand_totals(&grand_totals, display_options);
+    Ok(())
+}
+
+/// Adds the prices of `products` to `totals` per currency, items without a detected currency
+/// counting in the default one
+fn add_currency_totals(totals: &mut BTreeMap<&'static str, f64>, products: &[Product]) {
+    for product in products {
+        *totals.entry(product.currency.unwrap_or(DEFAULT_CURRENCY)).or_default() += product.price;
+    }
+}
+
+/// "Grand total: 12.50€", or one "Grand total EUR: 12.50€" per currency when they are mixed
+fn grand_total_lines(totals: &BTreeMap<&str, f64>, options: DisplayOptions<'_>) -> Vec<String> {
+    if totals.is_empty() {
+        return vec![format!("Grand total: {}", options.format_amount(0.0, DEFAULT_CURRENCY))];
+    }
+    let mixed_currencies = totals.len() > 1;
+    totals
+        .iter()
+        .map(|(currency, total)| {
+            let label = if mixed_currencies { format!("Grand total {}", currency) } else { "Grand total".to_string() };
+            format!("{}: {}", label, options.format_amount(*total, currency))
+        })
+        .collect()
+}
+
+fn print_grand_totals(totals: &BTreeMap<&str, f64>, options: DisplayOptions<'_>) {
+    println!();
+    for line in grand_total_lines(totals, options) {
+        println!("{}", line);
+    }
+}
+
+/// The items of one receipt with repeated articles counted, named "apfel ×2"
+fn counted_items(receipt: &Receipt) -> Vec<AggregatedProduct> {
+    let mut items = count_identical_items(receipt.items.clone());
+    for item in items.iter_mut().filter(|item| item.count > 1) {
+        item.name = format!("{} ×{}", item.name, item.count);
+    }
+    items
+}
+
+/// Prints the spending per month and currency recorded by --db
+pub fn display_monthly_report(totals: &[db::MonthlyTotal], options: DisplayOptions<'_>) {
+    if totals.is_empty() {
+        println!("No receipts recorded yet.");
+        return;
+    }
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_BORDERS_ONLY);
+    table.set_titles(Row::new(vec![
+        Cell::new("Month"),
+        Cell::new("Receipts").style_spec("r"),
+        Cell::new("Total").style_spec("r"),
+    ]));
+    for month in totals {
+        table.add_row(Row::new(vec![
+            Cell::new(month.month.as_deref().unwrap_or("undated")),
+            Cell::new(&month.receipts.to_string()).style_spec("r"),
+            Cell::new(&options.format_amount(month.total, &month.currency)).style_spec("r"),
+        ]));
+    }
+    table.printstd();
+}
+
+/// The `count` receipts with the highest totals, highest first
+fn top_receipts(receipts: &[Receipt], count: usize) -> Vec<ReceiptSummary<'_>> {
+    let mut receipts: Vec<ReceiptSummary> = receipts
+        .iter()
+        .map(|receipt| ReceiptSummary {
+            path: &receipt.source,
+            store: receipt.store.as_deref(),
+            date: receipt.date.map(|date| date.to_string()),
+            item_count: receipt.items.iter().map(|p| p.quantity as usize).sum(),
+            // A receipt is priced in one currency; an item without a currency marker doesn't say otherwise
+            currency: receipt.items.iter().find_map(|p| p.currency).unwrap_or(DEFAULT_CURRENCY),
+            total: receipt.item_total(),
+        })
+        .collect();
+    receipts.sort_by(|a, b| b.total.total_cmp(&a.total).then_with(|| a.path.cmp(b.path)));
+    receipts.truncate(count);
+    receipts
+}
+
+/// Prints the --highlight-receipts ranking
+pub fn display_top_receipts(receipts: &[Receipt], count: usize, options: DisplayOptions<'_>) {
+    if receipts.is_empty() {
+        return;
+    }
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_BORDERS_ONLY);
+    table.set_titles(Row::new(vec![
+        Cell::new("#"),
+        Cell::new("Receipt"),
+        Cell::new("Store"),
+        Cell::new("Date"),
+        Cell::new("Items"),
+        Cell::new("Total"),
+    ]));
+    for (rank, receipt) in top_receipts(receipts, count).iter().enumerate() {
+        table.add_row(Row::new(vec![
+            Cell::new(&(rank + 1).to_string()),
+            Cell::new(&receipt.path.display().to_string()),
+            Cell::new(receipt.store.unwrap_or(UNKNOWN_STORE)),
+            Cell::new(receipt.date.as_deref().unwrap_or(NO_DATE)),
+            Cell::new(&receipt.item_count.to_string()),
+            Cell::new(&options.format_amount(receipt.total, receipt.currency)),
+        ]));
+    }
+
+    println!("\nTop receipts by total");
+    table.printstd();
+}
+
+/// Prints the --stats summary
+pub fn display_stats(stats: &Stats, options: DisplayOptions<'_>) {
+    let amount = |value: Option<f64>| match value {
+        Some(value) => options.format_amount(value, DEFAULT_CURRENCY),
+        None => "-".to_string(),
+    };
+
+    println!("\nStatistics");
+    println!("  Unique products: {}", stats.unique_products);
+    println!("  Line items: {}", stats.line_items);
+    println!("  Mean item price: {}", amount(stats.mean_price));
+    println!("  Median item price: {}", amount(stats.median_price));
+    if let Some((name, price)) = &stats.most_expensive {
+        println!("  Most expensive item: {} ({})", name, amount(Some(*price)));
+    }
+    if stats.line_items > 0 {
+        let detected: Vec<String> =
+            stats.detected_currencies.iter().map(|(currency, items)| format!("{} {}", currency, items)).collect();
+        let detected = if detected.is_empty() { String::new() } else { format!(" ({})", detected.join(", ")) };
+        println!(
+            "  Currency detected: {} of {} items{}, {} assumed {}",
+            stats.line_items - stats.assumed_currency,
+            stats.line_items,
+            detected,
+            stats.assumed_currency,
+            options.currency_symbol
+        );
+    }
+}
+
+/// Prints the VAT read from the tax lines of all receipts, per rate and in total. Nothing if no
+/// receipt had any
+pub fn display_tax_summary(receipts: &[Receipt], options: DisplayOptions<'_>) {
+    // Keyed by hundredths of a percent so 19 and 19,00 are the same rate
+    let mut by_rate: BTreeMap<i64, (f64, f64)> = BTreeMap::new();
+    for &(rate, amount) in receipts.iter().flat_map(|receipt| &receipt.tax_breakdown) {
+        by_rate.entry((rate * 100.0).round() as i64).or_insert((rate, 0.0)).1 += amount;
+    }
+    if by_rate.is_empty() {
+        return;
+    }
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_BORDERS_ONLY);
+    table.set_titles(Row::new(vec![Cell::new("Rate"), Cell::new("Tax")]));
+    for (rate, amount) in by_rate.values() {
+        let rate = format!("{}%", rate);
+        let rate = if options.decimal_comma { rate.replace('.', ",") } else { rate };
+        table.add_row(Row::new(vec![
+            Cell::new(&rate).style_spec("r"),
+            Cell::new(&options.format_amount(*amount, DEFAULT_CURRENCY)).style_spec("r"),
+        ]));
+    }
+    let total: f64 = by_rate.values().map(|(_, amount)| amount).sum();
+    table.add_row(Row::new(vec![
+        Cell::new("TOTAL"),
+        Cell::new(&options.format_amount(total, DEFAULT_CURRENCY)).style_spec("br"),
+    ]));
+
+    println!("\nTax summary");
+    table.printstd();
+}
+
+/// Prints what the products of all receipts add up to per --categories category, with the
+/// uncategorized ones last
+pub fn display_category_summary(receipts: &[Receipt], categories: &Categories, options: DisplayOptions<'_>) {
+    let products: Vec<Product> = receipts.iter().flat_map(|receipt| receipt.items.iter().cloned()).collect();
+    let totals = category_totals(&products, categories);
+    if totals.is_empty() {
+        return;
+    }
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_BORDERS_ONLY);
+    table.set_titles(Row::new(vec![Cell::new("Category"), Cell::new("Items").style_spec("r"), Cell::new("Total")]));
+    let mut grand_totals: BTreeMap<&str, f64> = BTreeMap::new();
+    for category in &totals {
+        table.add_row(Row::new(vec![
+            Cell::new(&category.name),
+            Cell::new(&category.count.to_string()).style_spec("r"),
+            Cell::new(&options.format_amount(category.total, category.currency)).style_spec("r"),
+        ]));
+        *grand_totals.entry(category.currency).or_default() += category.total;
+    }
+    let mixed_currencies = grand_totals.len() > 1;
+    for (currency, total) in grand_totals {
+        let label = if mixed_currencies { format!("TOTAL {}", currency) } else { "TOTAL".to_string() };
+        table.add_row(Row::new(vec![
+            Cell::new(&label),
+            Cell::new(""),
+            Cell::new(&options.format_amount(total, currency)).style_spec("br"),
+        ]));
+    }
+
+    println!("\nCategories");
+    table.printstd();
+}
+
+/// Prints the --show-unmatched lines of every receipt that has some
+pub fn display_unmatched(receipts: &[Receipt]) {
+    for receipt in receipts.iter().filter(|receipt| !receipt.unmatched.is_empty()) {
+        println!("\nUnmatched lines in {}", receipt.source.display());
+        for line in &receipt.unmatched {
+            println!("  {}", line);
+        }
+    }
+}
+
+/// Symbol printed after amounts in the given currency
+fn currency_symbol(currency: &str) -> &str {
+    match currency {
+        "EUR" => "€",
+        "USD" => "$",
+        "GBP" => "£",
+        other => other,
+    }
+}
+
+fn display_results(products: Vec<AggregatedProduct>, options: DisplayOptions<'_>) -> Result<()> {
+    match options.format {
+        OutputFormat::Table => display_table(&products, options),
+        OutputFormat::Csv => write_csv(&products, options)?,
+        OutputFormat::Json | OutputFormat::Html => unreachable!("display_layout writes JSON and HTML as a whole"),
+    }
+    Ok(())
+}
+
+/// The products to list: the first `top` of them, which are the most expensive since
+/// `aggregate_products` sorts by total
+fn listed_products(products: &[AggregatedProduct], top: Option<usize>) -> &[AggregatedProduct] {
+    &products[..top.map_or(products.len(), |top| top.min(products.len()))]
+}
+
+/// Whether any listed product has several units, which is when the quantity and unit price
+/// columns say more than the total
+fn has_quantities(products: &[AggregatedProduct]) -> bool {
+    products.iter().any(|product| product.count > 1)
+}
+
+/// A column listed between a product's name and its total
+#[derive(Clone, Copy)]
+enum Column {
+    Count,
+    Units,
+    Average,
+    Min,
+    Max,
+}
+
+impl Column {
+    /// Heading in tables and HTML
+    fn title(self) -> &'static str {
+        match self {
+            Column::Count => "Count",
+            Column::Units => "Qty",
+            Column::Average => "Avg Unit",
+            Column::Min => "Min Unit",
+            Column::Max => "Max Unit",
+        }
+    }
+
+    /// CSV header
+    fn key(self) -> &'static str {
+        match self {
+            Column::Count => "count",
+            Column::Units => "units",
+            Column::Average => "average",
+            Column::Min => "min",
+            Column::Max => "max",
+        }
+    }
+
+    /// The unit price in the column, `None` for the counts
+    fn amount(self, product: &AggregatedProduct) -> Option<f64> {
+        match self {
+            Column::Count | Column::Units => None,
+            Column::Average => product.unit_price(),
+            Column::Min => Some(product.min_unit_price),
+            Column::Max => Some(product.max_unit_price),
+        }
+    }
+}
+
+/// The columns --aggregate lists before the total. Sums only get the quantity and average unit
+/// price when `units` asks for them
+fn statistic_columns(aggregation: Aggregation, units: bool) -> &'static [Column] {
+    match aggregation {
+        Aggregation::Sum if units => &[Column::Units, Column::Average],
+        Aggregation::Sum => &[],
+        Aggregation::Count => &[Column::Count, Column::Units],
+        Aggregation::Avg => &[Column::Average],
+        Aggregation::Minmax => &[Column::Min, Column::Max],
+    }
+}
+
+/// Gross and net totals per currency; the net total is `None` unless every product has a
+/// known VAT class
+fn grand_totals(products: &[AggregatedProduct]) -> BTreeMap<&'static str, (f64, Option<f64>)> {
+    let mut totals: BTreeMap<&str, (f64, Option<f64>)> = BTreeMap::new();
+    for product in products {
+        let (total, net_total) = totals.entry(product.currency).or_insert((0.0, Some(0.0)));
+        *total += product.total;
+        *net_total = net_total.zip(product.net_total).map(|(a, b)| a + b);
+    }
+    totals
+}
+
+fn display_table(products: &[AggregatedProduct], options: DisplayOptions<'_>) {
+    if products.is_empty() {
+        println!("No products found in receipt images.");
+        return;
+    }
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_BORDERS_ONLY);
+    // Amounts are right-aligned so the decimal points line up; credits are red
+    let amount_style = |amount: f64| if options.color && amount < 0.0 { "rFr" } else { "r" };
+    // Blank for amounts that can't be computed, such as the net price without a VAT class
+    let amount_cell = |amount: Option<f64>, currency: &str| {
+        let text = amount.map(|amount| options.format_amount(amount, currency)).unwrap_or_default();
+        Cell::new(&text).style_spec(amount_style(amount.unwrap_or_default()))
+    };
+
+    let listed = listed_products(products, options.top);
+    let columns = statistic_columns(options.aggregation, has_quantities(listed));
+    let mut titles = vec![Cell::new("Product Name")];
+    titles.extend(columns.iter().map(|column| Cell::new(column.title()).style_spec("r")));
+    titles.push(Cell::new("Total Price").style_spec("r"));
+    if options.show_net {
+        titles.push(Cell::new("Net Price").style_spec("r"));
+        titles.push(Cell::new("VAT").style_spec("r"));
+    }
+    table.set_titles(Row::new(titles));
+
+    for product in listed {
+        let currency = product.currency;
+        let mut cells = vec![Cell::new(&product.name)];
+        for &column in columns {
+            cells.push(match column {
+                Column::Count => Cell::new(&product.appearances.to_string()).style_spec("r"),
+                Column::Units => Cell::new(&product.count.to_string()).style_spec("r"),
+                _ => amount_cell(column.amount(product), currency),
+            });
+        }
+        cells.push(amount_cell(Some(product.total), currency));
+        if options.show_net {
+            let tax = product.net_total.map(|net| product.total - net);
+            cells.push(amount_cell(product.net_total, currency));
+            cells.push(amount_cell(tax, currency));
+        }
+        table.add_row(Row::new(cells));
+    }
+
+    let grand_totals = grand_totals(products);
+    let mixed_currencies = grand_totals.len() > 1;
+    for (currency, (total, net_total)) in grand_totals {
+        let label = if mixed_currencies { format!("TOTAL {}", currency) } else { "TOTAL".to_string() };
+        // Green when money was spent, red when credits outweigh the purchases
+        let total_style = match (options.color, total < 0.0) {
+            (false, _) => "br",
+            (true, false) => "bFgr",
+            (true, true) => "bFrr",
+        };
+        let mut total_cells = vec![Cell::new(&label)];
+        // Units and unit prices of different products don't add up to anything meaningful
+        total_cells.extend(columns.iter().map(|_| Cell::new("")));
+        total_cells.push(Cell::new(&options.format_amount(total, currency)).style_spec(total_style));
+        if options.show_net {
+            total_cells.push(amount_cell(net_total, currency));
+            total_cells.push(amount_cell(net_total.map(|net| total - net), currency));
+        }
+        table.add_row(Row::new(total_cells));
+    }
+
+    table.printstd();
+    let listed = listed.len();
+    if listed < products.len() {
+        println!("\nFound {} unique products (showing top {} of {})", products.len(), listed, products.len());
+    } else {
+        println!("\nFound {} unique products", products.len());
+    }
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    products: Vec<JsonProduct<'a>>,
+    /// Keyed by ISO currency code
+    #[serde(serialize_with = "serialize_cents_map")]
+    grand_total: BTreeMap<&'a str, f64>,
+    summary: RunSummary,
+    /// Only with --highlight-receipts, amounts rounded like the totals
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_receipts: Option<Vec<ReceiptSummary<'a>>>,
+    /// Only with --stats
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<JsonStats<'a>>,
+    /// Only with --per-receipt, in the order the receipts were read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receipts: Option<Vec<JsonReceipt<'a>>>,
+}
+
+/// One --per-receipt section: where the receipt came from and its items, counted like the table
+#[derive(Serialize)]
+struct JsonReceipt<'a> {
+    source: &'a Path,
+    /// Null when not found; the table says "Unknown store" / "no date" instead
+    store: Option<&'a str>,
+    date: Option<String>,
+    products: Vec<JsonProduct<'a>>,
+    /// Keyed by ISO currency code
+    #[serde(serialize_with = "serialize_cents_map")]
+    total: BTreeMap<&'static str, f64>,
+}
+
+#[derive(Serialize)]
+struct JsonStats<'a> {
+    unique_products: usize,
+    line_items: usize,
+    #[serde(serialize_with = "serialize_optional_cents")]
+    mean_price: Option<f64>,
+    #[serde(serialize_with = "serialize_optional_cents")]
+    median_price: Option<f64>,
+    most_expensive: Option<JsonItem<'a>>,
+    /// Items per detected currency, keyed by ISO code
+    currency_detected: &'a BTreeMap<&'static str, usize>,
+    /// Items counted in the default currency
+    currency_assumed: usize,
+}
+
+#[derive(Serialize)]
+struct JsonItem<'a> {
+    name: &'a str,
+    #[serde(serialize_with = "serialize_cents")]
+    price: f64,
+}
+
+#[derive(Serialize)]
+struct JsonProduct<'a> {
+    name: &'a str,
+    currency: &'a str,
+    #[serde(serialize_with = "serialize_cents")]
+    total: f64,
+    /// Only with --show-net, and only when the VAT class of every merged item is known
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_cents")]
+    net_total: Option<f64>,
+    /// The --aggregate statistic, nothing for sums
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    units: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_cents")]
+    average: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_cents")]
+    min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_cents")]
+    max: Option<f64>,
+}
+
+/// Writes the products of `receipts` as one JSON report, amounts rounded to cents. With
+/// `per_receipt`, every receipt also gets its own section
+fn write_json(
+    receipts: &[Receipt],
+    per_receipt: bool,
+    matcher: &dyn NameMatcher,
+    aggregate_options: &AggregateOptions,
+    options: DisplayOptions<'_>,
+) -> Result<()> {
+    let items: Vec<Product> = receipts.iter().flat_map(|receipt| receipt.items.iter().cloned()).collect();
+    let products = aggregate_products(items.clone(), matcher, aggregate_options);
+    let run_stats = options.stats.then(|| stats(&products, &items));
+    let round = |amount: f64| round_price(amount, options.rounding);
+    let receipt_items: Vec<Vec<AggregatedProduct>> =
+        if per_receipt { receipts.iter().map(counted_items).collect() } else { Vec::new() };
+    let report = JsonReport {
+        products: listed_products(&products, options.top)
+            .iter()
+            .map(|product| json_product(product, options))
+            .collect(),
+        grand_total: grand_totals(&products)
+            .into_iter()
+            .map(|(currency, (total, _))| (currency, round(total)))
+            .collect(),
+        summary: options.summary,
+        top_receipts: options.highlight_receipts.map(|count| {
+            let mut top = top_receipts(receipts, count);
+            for receipt in &mut top {
+                receipt.total = round(receipt.total);
+            }
+            top
+        }),
+        stats: run_stats.as_ref().map(|stats| JsonStats {
+            unique_products: stats.unique_products,
+            line_items: stats.line_items,
+            mean_price: stats.mean_price.map(round),
+            median_price: stats.median_price.map(round),
+            most_expensive: stats.most_expensive.as_ref().map(|(name, price)| JsonItem { name, price: round(*price) }),
+            currency_detected: &stats.detected_currencies,
+            currency_assumed: stats.assumed_currency,
+        }),
+        receipts: per_receipt.then(|| {
+            receipts
+                .iter()
+                .zip(&receipt_items)
+                .map(|(receipt, items)| {
+                    let mut total: BTreeMap<&str, f64> = BTreeMap::new();
+                    add_currency_totals(&mut total, &receipt.items);
+                    JsonReceipt {
+                        source: &receipt.source,
+                        store: receipt.store.as_deref(),
+                        date: receipt.date.map(|date| date.to_string()),
+                        products: items.iter().map(|product| json_product(product, options)).collect(),
+                        total: total.into_iter().map(|(currency, total)| (currency, round(total))).collect(),
+                    }
+                })
+                .collect()
+        }),
+    };
+
+    let mut output = open_output(options.output)?;
+    serde_json::to_writer_pretty(&mut output, &report)?;
+    writeln!(output)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// One product of a JSON report, with the --aggregate statistic and --show-net if asked for
+fn json_product<'a>(product: &'a AggregatedProduct, options: DisplayOptions<'_>) -> JsonProduct<'a> {
+    // The unit price in `column` when --aggregate asks for it
+    let statistic = |aggregation: Aggregation, column: Column| {
+        column
+            .amount(product)
+            .filter(|_| options.aggregation == aggregation)
+            .map(|amount| round_price(amount, options.rounding))
+    };
+    JsonProduct {
+        name: &product.name,
+        currency: product.currency,
+        total: round_price(product.total, options.rounding),
+        net_total: product
+            .net_total
+            .filter(|_| options.show_net)
+            .map(|net| round_price(net, options.rounding)),
+        count: (options.aggregation == Aggregation::Count).then_some(product.appearances),
+        units: (options.aggregation == Aggregation::Count).then_some(product.count),
+        average: statistic(Aggregation::Avg, Column::Average),
+        min: statistic(Aggregation::Minmax, Column::Min),
+        max: statistic(Aggregation::Minmax, Column::Max),
+    }
+}
+
+/// Writes one RFC 4180 CSV row per product, amounts with two decimals and no symbol
+fn write_csv(products: &[AggregatedProduct], options: DisplayOptions<'_>) -> Result<()> {
+    let format_price = |price: f64| format!("{:.2}", round_price(price, options.rounding));
+
+    let mut writer = csv::Writer::from_writer(open_output(options.output)?);
+    let columns = statistic_columns(options.aggregation, false);
+    let mut header = vec!["name", "currency"];
+    header.extend(columns.iter().map(|column| column.key()));
+    header.push("total");
+    if options.show_net {
+        header.extend(["net_total", "vat"]);
+    }
+    writer.write_record(&header)?;
+
+    for product in listed_products(products, options.top) {
+        let mut record = vec![product.name.clone(), product.currency.to_string()];
+        for &column in columns {
+            record.push(match column {
+                Column::Count => product.appearances.to_string(),
+                Column::Units => product.count.to_string(),
+                _ => column.amount(product).map(format_price).unwrap_or_default(),
+            });
+        }
+        record.push(format_price(product.total));
+        if options.show_net {
+            record.push(product.net_total.map(format_price).unwrap_or_default());
+            record.push(product.net_total.map(|net| format_price(product.total - net)).unwrap_or_default());
+        }
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Start of the HTML report up to the opening body tag, with the stylesheet inlined so the file
+/// can be shared on its own
+const HTML_HEAD: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>Receipt report</title>
+<style>
+body { font-family: system-ui, sans-serif; color: #222; max-width: 48em; margin: 2em auto; padding: 0 1em; }
+header p { color: #666; }
+table { border-collapse: collapse; width: 100%; margin: 0.5em 0 1.5em; }
+th, td { padding: 0.3em 0.6em; text-align: left; border-bottom: 1px solid #ddd; }
+th { background: #f4f4f4; }
+.amount { text-align: right; font-variant-numeric: tabular-nums; white-space: nowrap; }
+.credit { color: #b00020; }
+tfoot td { font-weight: bold; border-top: 2px solid #222; border-bottom: none; }
+</style>
+</head>
+<body>
+"#;
+
+/// Writes the report as a self-contained HTML page: a header naming the input and the time of
+/// the run, then one table of all products or, with --per-receipt, one section per receipt
+fn write_html(
+    receipts: &[Receipt],
+    per_receipt: bool,
+    matcher: &dyn NameMatcher,
+    aggregate_options: &AggregateOptions,
+    options: DisplayOptions<'_>,
+) -> Result<()> {
+    let generated = DateTime::<Utc>::from(SystemTime::now()).format("%Y-%m-%d %H:%M UTC");
+    let mut page = String::from(HTML_HEAD);
+    page.push_str(&format!(
+        "<header>\n<h1>Receipt report</h1>\n<p>{} &middot; {}</p>\n</header>\n",
+        escape_html(&join_paths(options.inputs)),
+        generated
+    ));
+
+    if per_receipt {
+        let mut grand_totals: BTreeMap<&str, f64> = BTreeMap::new();
+        for receipt in receipts {
+            add_currency_totals(&mut grand_totals, &receipt.items);
+            page.push_str(&format!("<section>\n<h2>{}</h2>\n", escape_html(&receipt.heading())));
+            page.push_str(&html_table(&counted_items(receipt), options));
+            page.push_str("</section>\n");
+        }
+        for line in grand_total_lines(&grand_totals, options) {
+            page.push_str(&format!("<p><strong>{}</strong></p>\n", escape_html(&line)));
+        }
+    } else {
+        let products: Vec<Product> = receipts.iter().flat_map(|receipt| receipt.items.iter().cloned()).collect();
+        let products = aggregate_products(products, matcher, aggregate_options);
+        page.push_str(&html_table(&products, options));
+        let listed = listed_products(&products, options.top).len();
+        if listed < products.len() {
+            page.push_str(&format!("<p>{} unique products, showing the top {}</p>\n", products.len(), listed));
+        } else {
+            page.push_str(&format!("<p>{} unique products</p>\n", products.len()));
+        }
+    }
+    page.push_str("</body>\n</html>\n");
+
+    let mut output = open_output(options.output)?;
+    output.write_all(page.as_bytes())?;
+    output.flush()?;
+    Ok(())
+}
+
+/// One product table with a total row per currency, amounts right-aligned and credits red
+fn html_table(products: &[AggregatedProduct], options: DisplayOptions<'_>) -> String {
+    if products.is_empty() {
+        return "<p>No products found in receipt images.</p>\n".to_string();
+    }
+
+    let amount = |value: Option<f64>, currency: &str| match value {
+        Some(value) => format!(
+            "<td class=\"amount{}\">{}</td>",
+            if value < 0.0 { " credit" } else { "" },
+            escape_html(&options.format_amount(value, currency))
+        ),
+        None => "<td class=\"amount\"></td>".to_string(),
+    };
+
+    let listed = listed_products(products, options.top);
+    let columns = statistic_columns(options.aggregation, has_quantities(listed));
+    let mut html = String::from("<table>\n<thead>\n<tr><th>Product</th>");
+    for column in columns {
+        html.push_str(&format!("<th class=\"amount\">{}</th>", column.title()));
+    }
+    html.push_str("<th class=\"amount\">Total</th>");
+    if options.show_net {
+        html.push_str("<th class=\"amount\">Net</th><th class=\"amount\">VAT</th>");
+    }
+    html.push_str("</tr>\n</thead>\n<tbody>\n");
+    for product in listed {
+        html.push_str(&format!("<tr><td>{}</td>", escape_html(&product.name)));
+        for &column in columns {
+            match column {
+                Column::Count => html.push_str(&format!("<td class=\"amount\">{}</td>", product.appearances)),
+                Column::Units => html.push_str(&format!("<td class=\"amount\">{}</td>", product.count)),
+                _ => html.push_str(&amount(column.amount(product), product.currency)),
+            }
+        }
+        html.push_str(&amount(Some(product.total), product.currency));
+        if options.show_net {
+            html.push_str(&amount(product.net_total, product.currency));
+            html.push_str(&amount(product.net_total.map(|net| product.total - net), product.currency));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</tbody>\n<tfoot>\n");
+
+    let grand_totals = grand_totals(products);
+    let mixed_currencies = grand_totals.len() > 1;
+    for (currency, (total, net_total)) in grand_totals {
+        let label = if mixed_currencies { format!("Total {}", currency) } else { "Total".to_string() };
+        html.push_str(&format!("<tr><td>{}</td>", escape_html(&label)));
+        html.push_str(&"<td></td>".repeat(columns.len()));
+        html.push_str(&amount(Some(total), currency));
+        if options.show_net {
+            html.push_str(&amount(net_total, currency));
+            html.push_str(&amount(net_total.map(|net| total - net), currency));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</tfoot>\n</table>\n");
+    html
+}
+
+/// Escapes text for use in HTML element content and quoted attribute values
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Opens the --output file, or stdout without one
+fn open_output(path: Option<&Path>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => {
+            let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+            Box::new(BufWriter::new(file))
+        }
+        None => Box::new(std::io::stdout().lock()),
+    })
+}
@@ -0,0 +1,1416 @@
+//! Turning OCR output into products: price patterns, skip rules, synonyms and the store, date
+//! and total of a receipt.
+
+use crate::{OcrLine, PaymentMethod, Product, Receipt, Weight};
+use anyhow::Result;
+use chrono::NaiveDate;
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Markers after an amount that make it a credit unless configured otherwise
+pub const DEFAULT_CREDIT_MARKERS: &[&str] = &["-", "CR", "H"];
+
+/// Upper price bound unless configured otherwise; larger amounts are usually OCR errors
+pub const DEFAULT_MAX_PRICE: f64 = 1000.0;
+
+/// Product name length bounds in characters unless configured otherwise
+pub const DEFAULT_MIN_NAME_LEN: usize = 3;
+pub const DEFAULT_MAX_NAME_LEN: usize = 40;
+
+/// Name of a unit price line ("3 @ 1,50") with no name on it or on the line above, so its amount
+/// still counts towards the totals
+pub const UNNAMED_ITEM: &str = "unnamed item";
+
+/// Settings that control how OCR text is turned into products
+pub struct ParseOptions {
+    /// Rules for headers, totals, taxes and other non-product lines
+    pub skip: SkipMatcher,
+    /// Collect every line that isn't a product with its [`SkipReason`] in [`Receipt::skipped`]
+    pub collect_skipped: bool,
+    /// Collect the lines that were neither skipped nor matched by a product pattern in
+    /// [`Receipt::unmatched`]
+    pub collect_unmatched: bool,
+    /// Markers printed after an amount that make it a credit, e.g. "-", "CR", "H" (Haben)
+    pub credit_markers: Vec<String>,
+    /// Items priced outside `min_price..=max_price` are dropped
+    pub min_price: f64,
+    pub max_price: f64,
+    /// Items read from lines with a lower OCR confidence are dropped
+    pub min_confidence: f32,
+    /// Bounds on the length of product names in characters, used by every item pattern. Longer
+    /// names are cut short or not matched at all
+    pub min_name_len: usize,
+    pub max_name_len: usize,
+    /// Product names replaced by a canonical name before aggregation
+    pub synonyms: Synonyms,
+    /// Keep the casing of product names as printed instead of lowercasing them. Aggregation
+    /// still compares names case-insensitively
+    pub preserve_case: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            skip: SkipMatcher::default(),
+            collect_skipped: false,
+            collect_unmatched: false,
+            credit_markers: DEFAULT_CREDIT_MARKERS.iter().map(|m| m.to_string()).collect(),
+            min_price: 0.0,
+            max_price: DEFAULT_MAX_PRICE,
+            min_confidence: 0.0,
+            min_name_len: DEFAULT_MIN_NAME_LEN,
+            max_name_len: DEFAULT_MAX_NAME_LEN,
+            synonyms: Synonyms::default(),
+            preserve_case: false,
+        }
+    }
+}
+
+/// Extracts the products from the OCR text of one receipt using the default [`ParseOptions`]
+pub fn parse_receipt_text(text: &str) -> Result<Vec<Product>> {
+    parse_receipt_text_with_options(text, &ParseOptions::default())
+}
+
+/// Extracts the products from the OCR text of one receipt. Plain text carries no confidence,
+/// every line counts as 100.
+pub fn parse_receipt_text_with_options(text: &str, options: &ParseOptions) -> Result<Vec<Product>> {
+    parse_receipt_from_text(text, options).map(|receipt| receipt.items)
+}
+
+/// Extracts the products, store, date and printed total from the OCR text of one receipt. Every
+/// line counts as confidence 100
+pub fn parse_receipt_from_text(text: &str, options: &ParseOptions) -> Result<Receipt> {
+    let lines: Vec<OcrLine> = text
+        .lines()
+        .map(|line| OcrLine { text: line.to_string(), confidence: 100.0 })
+        .collect();
+    parse_receipt(&lines, options)
+}
+
+/// Extracts the products from the OCR lines of one receipt
+pub fn parse_ocr_lines(lines: &[OcrLine], options: &ParseOptions) -> Result<Vec<Product>> {
+    parse_receipt(lines, options).map(|receipt| receipt.items)
+}
+
+/// Extracts the products, store, date and printed total from the OCR lines of one receipt
+pub fn parse_receipt(lines: &[OcrLine], options: &ParseOptions) -> Result<Receipt> {
+    let mut products: Vec<Product> = Vec::new();
+    let mut unmatched: Vec<String> = Vec::new();
+    let mut skipped: Vec<(SkipReason, String)> = Vec::new();
+    let mut skip = |reason: SkipReason, line: &str| {
+        if options.collect_skipped {
+            skipped.push((reason, line.to_string()));
+        }
+    };
+    let mut payment = None;
+    let mut printed_total = None;
+    let mut tax_breakdown = Vec::new();
+    let in_price_range = |price: f64| price >= options.min_price && price <= options.max_price;
+    let product_name =
+        |name: &str| if options.preserve_case { tidy_product_name(name) } else { clean_product_name(name) };
+
+    // Product names: a letter followed by `min_name_len - 1` to `max_name_len - 1` more characters
+    let name_len =
+        format!("{{{},{}}}", options.min_name_len.saturating_sub(1), options.max_name_len.saturating_sub(1));
+    // "{price}" and "{amount}" stand for PRICE and AMOUNT, so thousands separators are read everywhere
+    let with_amounts = |pattern: &str| Regex::new(&pattern.replace("{price}", PRICE).replace("{amount}", AMOUNT));
+    let with_name_len = |pattern: &str| with_amounts(&pattern.replace("{name_len}", &name_len));
+
+    // Enhanced patterns for multiple receipt formats. Prices may also use the whole-euro
+    // shorthand "3.-" / "3,–"; currency codes next to the amount are stripped beforehand
+
+    // Item sold by weight - "Tomaten 0,384 kg x 2,99 EUR/kg 1,15", "0,384kg x 2,99/kg 1,15"; the
+    // name may be on the line above
+    let pattern_weight = with_name_len(r"(?i)^(?:([A-Za-zÄÖÜäöüß][A-Za-zÄÖÜäöüß0-9\s\-.*]{name_len}?)\s+)?(\d+(?:[,.]\d{1,3})?)\s*kg\s*[x×*]\s*({amount})\s*(?:EUR|€)?\s*/\s*kg\s+({price})")?;
+
+    // Quantity at unit price with an optional line total - "Cola 2 @ 3,00 6,00"; the name may be
+    // on the line above
+    let pattern_unit_price = with_name_len(r"^(?:([A-Za-zÄÖÜäöüß][A-Za-zÄÖÜäöüß0-9\s\-.*]{name_len}?)\s+)?(\d+)\s*@\s*({price})(?:\s+({price}))?")?;
+
+    // Pattern 1: German format with quantity and total - "4x Löwenbräu Original a 3,00 12,00". The
+    // name is lazy so the "a"/"à"/"x" before the unit price stays out of it
+    let pattern_qty_total = with_name_len(r"(\d+|[IilL])x?\s+([A-Za-zÄÖÜäöüß][A-Za-zÄÖÜäöüß0-9\s\-.]{name_len}?)\s+(?:[aàx]\s+)?(?:{price}\s+)?({price})")?;
+
+    // Pattern 2: Euro format - "1 CHICKEN HEALS €9.99" or "2° PIZZA €25.98"
+    let pattern_euro = with_name_len(r"(\d+)°?\s+([A-Z][A-Z0-9\s\-.]{name_len})\s+€({price}|\d{3,})")?;
+
+    // Pattern 3: Simple product line - "EXTRA SPYCIES €0.00"
+    let pattern_euro_simple = with_name_len(r"([A-Z][A-Z0-9\s\-.]{name_len})\s+€({price}|\d{3,})")?;
+
+    // Pattern 4: German simple - "1 Cheeseburger* 1,19"
+    let pattern_de_simple = with_name_len(r"(\d+|[IilL])x?\s+([A-Za-zÄÖÜäöüß][A-Za-zÄÖÜäöüß0-9\s\-.*]{name_len})\s+[$£€]?({price})")?;
+
+    // Pattern 5: Product name followed by price - fallback, also "Milk $2.50"
+    let pattern_fallback = with_name_len(r"([A-Za-zÄÖÜäöüß][A-Za-zÄÖÜäöüß0-9\s\-.]{name_len})\s+[$£€]?({price})")?;
+
+    // Pattern 6: Price before the name - "4,99 Butter", "4,99 € Butter"; only tried when no
+    // name-first pattern matched
+    let pattern_price_first = with_name_len(r"^[$£€]?({price})\s*(?:€|EUR)?\s+([A-Za-zÄÖÜäöüß][A-Za-zÄÖÜäöüß0-9\s\-.]{name_len})$")?;
+
+    // Standalone price token, used to detect several items merged onto one line
+    let pattern_price_token = Regex::new(PRICE_TOKEN)?;
+
+    // Amount with a unit inside a name - "Vittel 1,50 l 0,89"
+    let pattern_unit_amount = Regex::new(&format!(r"(?i)\b(\d+),(\d+)(\s*(?:{})\b)", QUANTITY_UNITS.join("|")))?;
+    // The comma of such an amount becomes a dot, which names may contain, so the amount stays in
+    // the name instead of being read as the price. Trailing zeros go, so "1,50 l" and "1,5 l" name
+    // the same product
+    let unit_amounts_in_name = |line: &str| {
+        pattern_unit_amount
+            .replace_all(line, |amount: &Captures| match amount[2].trim_end_matches('0') {
+                "" => format!("{}{}", &amount[1], &amount[3]),
+                fraction => format!("{}.{}{}", &amount[1], fraction, &amount[3]),
+            })
+            .into_owned()
+    };
+
+    // VAT class letter closing a German product line - "Milch 1,19 A", "Milch 1,19 EUR A"
+    let pattern_vat_class = Regex::new(r"(?:\d[,.](?:\d{2}|[-–])|€|\bEUR)\s*([AB])\s*\*?$")?;
+
+    // Currency code written as a word before or after the amount - "EUR 3,50" / "3,50 EUR"
+    let pattern_currency_word = with_amounts(r"(?i)\b(?:EUR|USD|GBP)\s*({amount})\b|\b({amount})\s*(?:EUR|USD|GBP)\b")?;
+
+    // Credit marker after the amount - "5,00-", "5,00 CR", "5,00 H" (configurable)
+    let pattern_credit_marker = if options.credit_markers.is_empty() {
+        None
+    } else {
+        let markers: Vec<String> = options.credit_markers.iter().map(|m| regex::escape(m)).collect();
+        Some(Regex::new(&format!(r"(\d[,.]\d{{2}})\s*(?:{})\s*$", markers.join("|")))?)
+    };
+
+    // Minus sign attached to the amount - "Rabatt -1,00" or "-0,50 Pfandrückgabe". A dash set off
+    // by spaces ("Milch - 1,19") only separates the name from the price
+    let pattern_leading_minus = with_amounts(r"(?:^|\s)-({amount})\b")?;
+
+    // Price cut off after the separator - "Kaffee 3," followed by a line holding just "50"
+    let pattern_dangling_price = Regex::new(r"[A-Za-zÄÖÜäöüß].*\s\d{1,4}[,.]$")?;
+    let pattern_price_cents = Regex::new(r"^\d{2}(?:\s*(?:[AB]|€|EUR))?$")?;
+
+    // Price alone on its line, below a name too long to share the line - "Bio-Vollkornbrot
+    // geschnitten" / "2,49 A"
+    let pattern_bare_price = with_amounts(r"^[$£€]?{price}(?:\s*(?:€|EUR))?(?:\s*[AB])?$")?;
+
+    // Receipt total - "Summe 12,00", "TOTAL: EUR 12.00", "Zu zahlen 12,00 €"
+    let pattern_printed_total = with_amounts(
+        r"(?i)^(?:summe|total|gesamt|gesamtsumme|gesamtbetrag|zu zahlen)\s*:?\s*(?:eur|€)?\s*(-?{amount})\s*(?:eur|€)?$",
+    )?;
+
+    // VAT line - "MwSt 19% 1,90", "7% USt 0,35", "A MwSt 19% 1,90 EUR"
+    let pattern_tax = with_amounts(
+        r"(?i)^(?:[AB]\s+)?(?:(?:mwst|ust|vat|tax)\.?\s*(\d{1,2}(?:[,.]\d{1,2})?)\s*%|(\d{1,2}(?:[,.]\d{1,2})?)\s*%\s*(?:mwst|ust|vat|tax)\.?)\s*:?\s*(?:eur|€)?\s*({amount})\s*(?:eur|€)?$",
+    )?;
+
+    // Receipt date - "12.03.2024", "12.03.24", "12/03/2024" or "2024-03-12"
+    let pattern_date = Regex::new(r"\b(?:(\d{1,2})[./](\d{1,2})[./](\d{4}|\d{2})|(\d{4})-(\d{2})-(\d{2}))\b")?;
+
+    // Percentage discount line - "-20% Aktion" or "Rabatt -10%"
+    let pattern_percent_discount = Regex::new(r"-\s*(\d{1,3}(?:[,.]\d+)?)\s*%")?;
+
+    let item_patterns = [
+        &pattern_weight,
+        &pattern_unit_price,
+        &pattern_qty_total,
+        &pattern_euro,
+        &pattern_euro_simple,
+        &pattern_de_simple,
+        &pattern_fallback,
+        &pattern_price_first,
+    ];
+
+    let text = lines.iter().map(|line| line.text.as_str()).collect::<Vec<_>>().join("\n");
+    let lines = rejoin_split_prices(lines, &pattern_dangling_price, &pattern_price_cents);
+    let lines = rejoin_wrapped_names(lines, &pattern_bare_price, &pattern_price_token);
+    let store = detect_store(&lines, &pattern_price_token);
+    // Dates are usually on lines the skip rules drop ("Datum: 12.03.2024"), so look before that
+    let date = find_receipt_date(&lines, &pattern_date);
+    let mut previous = None;
+    // Products found before the previous line, to tell whether that line produced one
+    let mut products_before_previous = 0;
+    for OcrLine { text, confidence } in &lines {
+        let (line, confidence) = (text.as_str(), *confidence);
+        // A unit price or weight line without a name ("2 @ 3,00") belongs to the name printed above it
+        let item_name_above = previous
+            .replace(line)
+            .filter(|above: &&str| {
+                above.chars().any(char::is_alphabetic)
+                    && !pattern_price_token.is_match(above)
+                    && options.skip.matches(above).is_none()
+            });
+        if line.is_empty() || line.len() < 4 {
+            continue;
+        }
+        let previous_line_was_item = products.len() > products_before_previous;
+        products_before_previous = products.len();
+
+        // Percentage discount for the item on the line before - "-20% Aktion", "Rabatt -10%".
+        // Checked before the skip rules, which drop every other line containing "%". Without an
+        // item right above, there is nothing it can safely apply to. After a product name
+        // ("Joghurt -20% 1,99") the printed price already has the discount, so only the
+        // percentage is dropped and the rest is read as an item
+        let mut without_percentage = None;
+        if let Some(captures) = pattern_percent_discount.captures(line)
+            && let Some(percentage) = captures.get(0) {
+            let before = &line[..percentage.start()];
+            if before.chars().any(char::is_alphabetic) && !is_discount_line(before) {
+                without_percentage = Some(format!("{} {}", before.trim_end(), line[percentage.end()..].trim_start()));
+            } else {
+                match parse_decimal(&captures[1]) {
+                    Ok(percent) if percent > 0.0 && percent <= 100.0 && previous_line_was_item => {
+                        if let Some(product) = products.last_mut() {
+                            product.price *= 1.0 - percent / 100.0;
+                        }
+                    }
+                    _ if options.collect_unmatched => unmatched.push(line.to_string()),
+                    _ => {}
+                }
+                continue;
+            }
+        }
+        let line = without_percentage.as_deref().unwrap_or(line);
+
+        // The printed total is kept for cross-checking before the skip rules discard it; the
+        // first one wins since card slips often repeat it further down
+        if let Some(captures) = pattern_printed_total.captures(line) {
+            if printed_total.is_none() {
+                printed_total = parse_european_price(&captures[1]).ok();
+            }
+            skip(SkipReason::TotalLine("printed total".to_string()), line);
+            continue;
+        }
+
+        // Tax lines are summarized per rate, then dropped like the other non-product lines
+        if let Some(captures) = pattern_tax.captures(line) {
+            let rate = captures.get(1).or(captures.get(2)).map(|rate| parse_decimal(rate.as_str()));
+            if let Some(Ok(rate)) = rate
+                && let Ok(amount) = parse_european_price(&captures[3]) {
+                tax_breakdown.push((rate, amount));
+            }
+            skip(SkipReason::TaxLine("tax line".to_string()), line);
+            continue;
+        }
+
+        // Payment lines ("Zahlung: Karte", "Bar 20,00") are recorded before the skip rules
+        // discard them; the first one on a receipt wins
+        if let Some(method) = detect_payment_method(line) {
+            payment = payment.or(Some(method));
+            skip(SkipReason::PaymentLine(format!("paid by {}", method.label())), line);
+            continue;
+        }
+
+        // Skip headers, totals, taxes, etc.
+        if let Some(reason) = options.skip.matches(line) {
+            skip(reason, line);
+            continue;
+        }
+
+        // Exported receipts with explicit separators are parsed by position, not by regex
+        if let Some((name, quantity, price)) = parse_separated_line(line) {
+            if in_price_range(price) {
+                products.push(Product {
+                    name: product_name(name),
+                    price,
+                    currency: detect_currency(line),
+                    vat_class: None,
+                    quantity,
+                    confidence,
+                    store: None,
+                    weight: None,
+                    line: line.to_string(),
+                });
+            }
+            continue;
+        }
+
+        // Narrow receipts sometimes get two or more rows merged into one OCR line
+        for segment in split_bundled_line(line, &pattern_price_token) {
+            let currency = detect_currency(segment);
+            let vat_class = pattern_vat_class
+                .captures(segment)
+                .and_then(|c| c.get(1))
+                .and_then(|m| m.as_str().chars().next());
+            // Credits marked after the amount ("5,00-", "5,00 CR") are parsed as positive and
+            // negated once the product has been extracted
+            let (line, is_credit) = match &pattern_credit_marker {
+                Some(pattern) if pattern.is_match(segment) => (pattern.replace(segment, "$1"), true),
+                _ => (Cow::Borrowed(segment), false),
+            };
+            // Discounts and refunds with a minus before the amount or a discount keyword are
+            // credits as well
+            let (line, has_leading_minus) = match strip_leading_minus(&line, &pattern_leading_minus) {
+                Some(stripped) => (Cow::Owned(stripped), true),
+                None => (line, false),
+            };
+            let is_credit = is_credit || has_leading_minus || is_discount_line(&line);
+            let first_new = products.len();
+            let line = strip_currency_word(&line, &pattern_currency_word);
+            let line = line.as_ref();
+
+            // Try patterns in order of specificity
+            if let Some(captures) = pattern_weight.captures(line) {
+                let name = captures.get(1).map(|m| m.as_str()).or(item_name_above);
+                if let (Some(name), Some(kg), Some(per_kg), Some(total)) =
+                    (name, captures.get(2), captures.get(3), captures.get(4))
+                    && let (Ok(kg), Ok(price_per_kg), Ok(price)) = (
+                        parse_decimal(kg.as_str()),
+                        parse_european_price(per_kg.as_str()),
+                        parse_european_price(total.as_str()),
+                    )
+                    && in_price_range(price) {
+                    products.push(Product {
+                        name: product_name(name),
+                        price,
+                        currency,
+                        vat_class,
+                        quantity: 1,
+                        confidence,
+                        store: None,
+                        weight: Some(Weight { kg, price_per_kg }),
+                        line: segment.to_string(),
+                    });
+                    if captures.get(1).is_none() {
+                        forget_unmatched(&mut unmatched, name);
+                    }
+                }
+            }
+            else if let Some(captures) = pattern_unit_price.captures(line) {
+                let name = captures.get(1).map(|m| m.as_str()).or(item_name_above).unwrap_or(UNNAMED_ITEM);
+                if let (Some(qty_str), Some(unit_str)) = (captures.get(2), captures.get(3))
+                    && let Ok(unit_price) = parse_european_price(unit_str.as_str()) {
+                    let quantity = parse_quantity(qty_str.as_str());
+                    let price = match captures.get(4).map(|m| parse_european_price(m.as_str())) {
+                        Some(Ok(total)) => total,
+                        _ => unit_price * f64::from(quantity),
+                    };
+                    if in_price_range(price) {
+                        products.push(Product {
+                            name: product_name(name),
+                            price,
+                            currency,
+                            vat_class,
+                            quantity,
+                            confidence,
+                            store: None,
+                            weight: None,
+                            line: segment.to_string(),
+                        });
+                        if captures.get(1).is_none() {
+                            forget_unmatched(&mut unmatched, name);
+                        }
+                    }
+                }
+            }
+            else if let Some(captures) = pattern_qty_total.captures(line) {
+                if let (Some(qty_str), Some(name), Some(price_str)) =
+                    (captures.get(1), captures.get(2), captures.get(3)) {
+                    // Handle OCR errors: "Ix" -> "1"
+                    let quantity = parse_quantity(qty_str.as_str());
+                    // With both a unit price and a line total the captured price is the total,
+                    // a lone price ("4x Bier a 3,00") is per unit
+                    let has_line_total = pattern_price_token.find_iter(line).count() >= 2;
+                    if let Ok(price) = parse_european_price(price_str.as_str()) {
+                        let price = if has_line_total { price } else { price * f64::from(quantity) };
+                        if in_price_range(price) {
+                            products.push(Product {
+                                name: product_name(name.as_str()),
+                                price,
+                                currency,
+                                vat_class,
+                                quantity,
+                                confidence,
+                                store: None,
+                                weight: None,
+                                line: segment.to_string(),
+                            });
+                        }
+                    }
+                }
+            }
+            else if let Some(captures) = pattern_euro.captures(line) {
+                if let (Some(qty_str), Some(name), Some(price_str)) =
+                    (captures.get(1), captures.get(2), captures.get(3))
+                    && let Ok(price) = parse_european_price(price_str.as_str())
+                    && in_price_range(price) {
+                    products.push(Product {
+                        name: product_name(name.as_str()),
+                        price,
+                        currency,
+                        vat_class,
+                        quantity: parse_quantity(qty_str.as_str()),
+                        confidence,
+                        store: None,
+                        weight: None,
+                        line: segment.to_string(),
+                    });
+                }
+            }
+            else if let Some(captures) = pattern_euro_simple.captures(line) {
+                if let (Some(name), Some(price_str)) = (captures.get(1), captures.get(2))
+                    && let Ok(price) = parse_european_price(price_str.as_str())
+                    && in_price_range(price) {
+                    products.push(Product {
+                        name: product_name(name.as_str()),
+                        price,
+                        currency,
+                        vat_class,
+                        quantity: 1,
+                        confidence,
+                        store: None,
+                        weight: None,
+                        line: segment.to_string(),
+                    });
+                }
+            }
+            else if let Some(captures) = pattern_de_simple.captures(line) {
+                if let (Some(qty_str), Some(name), Some(price_str)) =
+                    (captures.get(1), captures.get(2), captures.get(3))
+                    && let Ok(price) = parse_european_price(price_str.as_str())
+                    && in_price_range(price) {
+                    products.push(Product {
+                        name: product_name(name.as_str()),
+                        price,
+                        currency,
+                        vat_class,
+                        quantity: parse_quantity(qty_str.as_str()),
+                        confidence,
+                        store: None,
+                        weight: None,
+                        line: segment.to_string(),
+                    });
+                }
+            }
+            else if let Some(captures) = pattern_fallback.captures(&unit_amounts_in_name(line))
+                && let (Some(name), Some(price_str)) = (captures.get(1), captures.get(2))
+                && let Ok(price) = parse_european_price(price_str.as_str())
+                && in_price_range(price) {
+                let name_str = name.as_str().trim();
+                if name_str.chars().count() >= options.min_name_len
+                    && !name_str.chars().all(|c| c.is_numeric() || c == '.' || c == ',' || c == '-') {
+                    products.push(Product {
+                        name: product_name(name_str),
+                        price,
+                        currency,
+                        vat_class,
+                        quantity: 1,
+                        confidence,
+                        store: None,
+                        weight: None,
+                        line: segment.to_string(),
+                    });
+                }
+            }
+            else if let Some(captures) = pattern_price_first.captures(line)
+                && let (Some(price_str), Some(name)) = (captures.get(1), captures.get(2))
+                && let Ok(price) = parse_european_price(price_str.as_str())
+                && in_price_range(price) {
+                // A name rather than a unit or code: mostly letters
+                let name_str = name.as_str().trim();
+                let letters = name_str.chars().filter(|c| c.is_alphabetic()).count();
+                let non_blank = name_str.chars().filter(|c| !c.is_whitespace()).count();
+                if letters >= options.min_name_len.min(3) && letters * 2 > non_blank {
+                    products.push(Product {
+                        name: product_name(name_str),
+                        price,
+                        currency,
+                        vat_class,
+                        quantity: 1,
+                        confidence,
+                        store: None,
+                        weight: None,
+                        line: segment.to_string(),
+                    });
+                }
+            }
+
+            if is_credit {
+                for product in &mut products[first_new..] {
+                    product.price = -product.price;
+                }
+            }
+            if options.collect_unmatched && !item_patterns.iter().any(|pattern| pattern.is_match(line)) {
+                unmatched.push(segment.to_string());
+            }
+        }
+    }
+
+    for product in &mut products {
+        product.store = store.clone();
+        if let Some(canonical) = options.synonyms.canonical(&clean_product_name(&product.name)) {
+            product.name = product_name(canonical);
+        }
+    }
+    products.retain(|product| product.confidence >= options.min_confidence);
+
+    Ok(Receipt {
+        source: PathBuf::new(),
+        store,
+        date,
+        items: products,
+        printed_total,
+        tax_breakdown,
+        payment,
+        unmatched,
+        skipped,
+        text,
+    })
+}
+
+/// Takes back the unmatched line a nameless unit price or weight line borrowed its name from
+fn forget_unmatched(unmatched: &mut Vec<String>, name_line: &str) {
+    if unmatched.last().is_some_and(|line| line == name_line) {
+        unmatched.pop();
+    }
+}
+
+/// Parses a line with explicit field separators, "Kaffee;2;3,50;7,00" or "Kaffee | 2 | 7,00",
+/// returning the name, quantity and line total. Fields are positional: name, quantity, [unit price,] total.
+/// Lines with fewer than three fields or fields that don't fit that layout return `None`.
+fn parse_separated_line(line: &str) -> Option<(&str, u32, f64)> {
+    let separator = [';', '|'].into_iter().find(|sep| line.matches(*sep).count() >= 2)?;
+    let fields: Vec<&str> = line.split(separator).map(str::trim).collect();
+
+    let (name, quantity, total) = match fields.as_slice() {
+        [name, quantity, total] => (*name, *quantity, *total),
+        [name, quantity, unit_price, total] => {
+            parse_european_price(unit_price).ok()?;
+            (*name, *quantity, *total)
+        }
+        _ => return None,
+    };
+
+    if !name.chars().any(char::is_alphabetic) {
+        return None;
+    }
+    let quantity = quantity.trim_end_matches(['x', 'X']).trim().parse::<u32>().ok()?;
+    let total = parse_european_price(total).ok()?;
+
+    Some((name, quantity, total))
+}
+
+/// Rejoins prices that OCR wrapped onto two lines ("Kaffee 3," / "50") and normalizes the
+/// whitespace of every line: tabs and runs of spaces between columns or inside names become a
+/// single space, so "Bio   Milch" counts as long as "Bio Milch" against the name length bounds.
+/// Only a line ending in a dangling separator followed by a line of just the cents is merged;
+/// the merged line keeps the lower confidence of the two.
+fn rejoin_split_prices(raw_lines: &[OcrLine], dangling_price: &Regex, price_cents: &Regex) -> Vec<OcrLine> {
+    let mut lines = Vec::new();
+    let mut raw_lines = raw_lines
+        .iter()
+        .map(|line| OcrLine {
+            text: line.text.split_whitespace().collect::<Vec<_>>().join(" "),
+            confidence: line.confidence,
+        })
+        .peekable();
+
+    while let Some(line) = raw_lines.next() {
+        if dangling_price.is_match(&line.text)
+            && let Some(next) = raw_lines.next_if(|next| price_cents.is_match(&next.text)) {
+            lines.push(OcrLine {
+                text: format!("{}{}", line.text, next.text),
+                confidence: line.confidence.min(next.confidence),
+            });
+        } else {
+            lines.push(line);
+        }
+    }
+
+    lines
+}
+
+/// Moves a price printed alone on its line up to the name above it, for names that filled the
+/// line ("Bio-Vollkornbrot geschnitten" / "2,49"). The name line needs a few letters and no price
+/// of its own, the price line nothing but the price and a VAT class; the merged line keeps the
+/// lower confidence of the two.
+fn rejoin_wrapped_names(lines: Vec<OcrLine>, bare_price: &Regex, price_token: &Regex) -> Vec<OcrLine> {
+    let mut joined = Vec::new();
+    let mut lines = lines.into_iter().peekable();
+
+    while let Some(line) = lines.next() {
+        if line.text.chars().filter(|c| c.is_alphabetic()).count() >= 2
+            && !price_token.is_match(&line.text)
+            && let Some(next) = lines.next_if(|next| bare_price.is_match(&next.text)) {
+            joined.push(OcrLine {
+                text: format!("{} {}", line.text, next.text),
+                confidence: line.confidence.min(next.confidence),
+            });
+        } else {
+            joined.push(line);
+        }
+    }
+
+    joined
+}
+
+/// A word of Tesseract's TSV output with its bounding box in pixels
+struct TsvWord<'a> {
+    /// Page, block, paragraph and line number Tesseract assigned the word to
+    line_key: (&'a str, &'a str, &'a str, &'a str),
+    left: i32,
+    top: i32,
+    width: i32,
+    height: i32,
+    confidence: f32,
+    text: &'a str,
+}
+
+impl TsvWord<'_> {
+    fn right(&self) -> i32 {
+        self.left + self.width
+    }
+
+    fn center_y(&self) -> f32 {
+        self.top as f32 + self.height as f32 / 2.0
+    }
+}
+
+/// The words of Tesseract's TSV output in reading order. Rows that aren't words (levels 1–4)
+/// and empty words are ignored.
+fn tsv_words(tsv: &str) -> Vec<TsvWord<'_>> {
+    let mut words = Vec::new();
+    for row in tsv.lines() {
+        // level page block paragraph line word left top width height conf text
+        let fields: Vec<&str> = row.splitn(12, '\t').collect();
+        if fields.len() < 12 || fields[0] != "5" {
+            continue;
+        }
+        let text = fields[11].trim();
+        let Ok(confidence) = fields[10].parse::<f32>() else {
+            continue;
+        };
+        if text.is_empty() || confidence < 0.0 {
+            continue;
+        }
+        let [Ok(left), Ok(top), Ok(width), Ok(height)] = [6, 7, 8, 9].map(|i| fields[i].parse::<i32>()) else {
+            continue;
+        };
+
+        words.push(TsvWord {
+            line_key: (fields[1], fields[2], fields[3], fields[4]),
+            left,
+            top,
+            width,
+            height,
+            confidence,
+            text,
+        });
+    }
+    words
+}
+
+/// Groups the words of Tesseract's TSV output into lines with their mean word confidence.
+/// Rows that aren't words (levels 1–4) and empty words are ignored.
+pub fn parse_tesseract_tsv(tsv: &str) -> Vec<OcrLine> {
+    let mut lines: Vec<OcrLine> = Vec::new();
+    let mut current_key = None;
+    let mut confidences: Vec<f32> = Vec::new();
+
+    for word in tsv_words(tsv) {
+        match lines.last_mut() {
+            Some(line) if current_key == Some(word.line_key) => {
+                line.text.push(' ');
+                line.text.push_str(word.text);
+            }
+            _ => {
+                if let Some(line) = lines.last_mut() {
+                    line.confidence = mean(&confidences);
+                }
+                confidences.clear();
+                current_key = Some(word.line_key);
+                lines.push(OcrLine { text: word.text.to_string(), confidence: 0.0 });
+            }
+        }
+        confidences.push(word.confidence);
+    }
+    if let Some(line) = lines.last_mut() {
+        line.confidence = mean(&confidences);
+    }
+
+    lines
+}
+
+/// Like `parse_tesseract_tsv`, but builds lines from the word positions instead of Tesseract's
+/// own line numbers, which break up receipts whose prices sit far to the right:
+///
+/// - Words whose vertical centers lie within half a word height of each other form one row,
+///   whichever block Tesseract put them in.
+/// - A row is cut into regions wherever the horizontal gap between two words is wider than
+///   twice the word height, more than any space between words of a name.
+/// - Each region with a price closes an item; regions before it are its name. "Milch | 1,19 |
+///   Brot | 2,49" becomes two lines, while "4x Bier | a 3,00 | 12,00" stays one because a price
+///   region without a name of its own belongs to the item before it.
+pub fn parse_tesseract_tsv_spatial(tsv: &str) -> Result<Vec<OcrLine>> {
+    let price_token = Regex::new(PRICE_TOKEN)?;
+    let mut words = tsv_words(tsv);
+    if words.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut heights: Vec<i32> = words.iter().map(|word| word.height).collect();
+    heights.sort_unstable();
+    let word_height = heights[heights.len() / 2].max(1) as f32;
+
+    // Rows top to bottom, each compared by the mean center of the words it already has
+    words.sort_by(|a, b| a.center_y().total_cmp(&b.center_y()));
+    let mut rows: Vec<Vec<TsvWord>> = Vec::new();
+    for word in words {
+        match rows.last_mut() {
+            Some(row) if (word.center_y() - row_center(row)).abs() <= word_height / 2.0 => row.push(word),
+            _ => rows.push(vec![word]),
+        }
+    }
+
+    let mut lines = Vec::new();
+    for mut row in rows {
+        row.sort_by_key(|word| word.left);
+
+        let mut regions: Vec<Vec<&TsvWord>> = Vec::new();
+        for (index, word) in row.iter().enumerate() {
+            let column_gap = index > 0 && (word.left - row[index - 1].right()) as f32 > 2.0 * word_height;
+            match regions.last_mut() {
+                Some(region) if !column_gap => region.push(word),
+                _ => regions.push(vec![word]),
+            }
+        }
+
+        let mut item: Vec<&TsvWord> = Vec::new();
+        let mut item_has_price = false;
+        for region in regions {
+            let text = region.iter().map(|word| word.text).collect::<Vec<_>>().join(" ");
+            let has_name = text.chars().filter(|c| c.is_alphabetic()).count() >= 2;
+            if has_name && item_has_price {
+                lines.push(words_to_line(&item));
+                item.clear();
+                item_has_price = false;
+            }
+            item_has_price |= price_token.is_match(&text);
+            item.extend(region);
+        }
+        lines.push(words_to_line(&item));
+    }
+
+    Ok(lines)
+}
+
+fn row_center(row: &[TsvWord]) -> f32 {
+    row.iter().map(TsvWord::center_y).sum::<f32>() / row.len() as f32
+}
+
+fn words_to_line(words: &[&TsvWord]) -> OcrLine {
+    let confidences: Vec<f32> = words.iter().map(|word| word.confidence).collect();
+    OcrLine {
+        text: words.iter().map(|word| word.text).collect::<Vec<_>>().join(" "),
+        confidence: mean(&confidences),
+    }
+}
+
+fn mean(values: &[f32]) -> f32 {
+    values.iter().sum::<f32>() / values.len().max(1) as f32
+}
+
+/// Units that make the number before them part of a product name: "Vittel 1,50 l"
+const QUANTITY_UNITS: &[&str] = &["l", "ml", "g", "kg", "ltr", "stk"];
+
+/// Splits an OCR line holding several "name price" pairs ("Brot 1,99 Milch 0,99") into one
+/// segment per item. Lines that don't clearly contain multiple items are returned unchanged.
+fn split_bundled_line<'a>(line: &'a str, price_token: &Regex) -> Vec<&'a str> {
+    let prices: Vec<_> = price_token.find_iter(line).collect();
+    if prices.len() < 2 {
+        return vec![line];
+    }
+
+    let mut bounds = Vec::new();
+    let mut start = 0;
+    for price in &prices {
+        let name = &line[start..price.start()];
+        // Every price needs its own name; "4x Bier a 3,00 12,00" has a unit and a line price
+        if name.chars().filter(|c| c.is_alphabetic()).count() < 2 {
+            return vec![line];
+        }
+        // A number followed by a unit is part of the name ("Vittel 1,50 l 0,89"), and one per
+        // kilogram ("2,99 EUR/kg 1,15") is a unit price, neither is a separate item
+        let next_word = line[price.end()..].split_whitespace().next().unwrap_or("").to_lowercase();
+        if QUANTITY_UNITS.contains(&next_word.as_str()) || next_word.ends_with("/kg") {
+            return vec![line];
+        }
+        bounds.push((start, price.end()));
+        start = price.end();
+    }
+
+    // Keep trailing markers (tax class, currency) with the last item
+    if let Some(last) = bounds.last_mut() {
+        last.1 = line.len();
+    }
+
+    bounds.into_iter().map(|(start, end)| line[start..end].trim()).collect()
+}
+
+/// Removes a currency code written next to the amount ("Kaffee EUR 3,50", "EUR 3,50 Kaffee") so it
+/// isn't captured as part of the product name. The currency itself is picked up by `detect_currency`.
+fn strip_currency_word<'a>(line: &'a str, pattern: &Regex) -> Cow<'a, str> {
+    let Some(captures) = pattern.captures(line) else {
+        return Cow::Borrowed(line);
+    };
+    let (Some(whole), Some(amount)) = (captures.get(0), captures.get(1).or(captures.get(2))) else {
+        return Cow::Borrowed(line);
+    };
+
+    let before = line[..whole.start()].trim();
+    let after = line[whole.end()..].trim();
+    if before.is_empty() {
+        // Amount printed first: move it behind the name like every other layout
+        Cow::Owned(format!("{} {}", after, amount.as_str()))
+    } else {
+        Cow::Owned(format!("{} {} {}", before, amount.as_str(), after).trim_end().to_string())
+    }
+}
+
+/// Removes a minus sign printed before the amount ("Rabatt -1,00", "-0,50 Pfandrückgabe") and
+/// moves a leading amount behind the name. Returns `None` if there is no such minus.
+fn strip_leading_minus(line: &str, pattern: &Regex) -> Option<String> {
+    let captures = pattern.captures(line)?;
+    let (whole, amount) = (captures.get(0)?, captures.get(1)?);
+
+    let before = line[..whole.start()].trim();
+    let after = line[whole.end()..].trim();
+    if before.is_empty() {
+        Some(format!("{} {}", after, amount.as_str()).trim().to_string())
+    } else {
+        Some(format!("{} {} {}", before, amount.as_str(), after).trim_end().to_string())
+    }
+}
+
+/// Words marking a discount or refund line whose amount is printed without a minus. A plain
+/// "Pfand" line is the deposit being charged, so only the deposit return counts.
+const DISCOUNT_KEYWORDS: &[&str] = &["rabatt", "pfandrückgabe", "pfandrueckgabe", "leergut"];
+
+fn is_discount_line(line: &str) -> bool {
+    let line_lower = line.to_lowercase();
+    DISCOUNT_KEYWORDS.iter().any(|keyword| line_lower.contains(keyword))
+}
+
+/// An amount on a receipt line: "1,99", "2.50", the whole-euro shorthand "3,-", and with
+/// thousands separators "1.299,00" or "1,234.56"
+const PRICE: &str = r"(?:\d{1,3}(?:[.,]\d{3})+|\d+)[,.](?:\d{2}|[-–])";
+
+/// [`PRICE`] with cents, for totals, tax and currency-code amounts
+const AMOUNT: &str = r"(?:\d{1,3}(?:[.,]\d{3})+|\d+)[,.]\d{2}";
+
+/// A standalone price, as opposed to digits inside a name
+const PRICE_TOKEN: &str = r"\b(?:\d{1,3}(?:[.,]\d{3})+|\d+)[,.](?:\d{2}\b|[-–])";
+
+/// Detects the currency a line is priced in from a currency symbol or ISO code
+fn detect_currency(line: &str) -> Option<&'static str> {
+    let has_code = |code: &str| {
+        line.split(|c: char| !c.is_alphabetic())
+            .any(|word| word.eq_ignore_ascii_case(code))
+    };
+
+    if line.contains('€') || has_code("EUR") {
+        Some("EUR")
+    } else if line.contains('$') || has_code("USD") {
+        Some("USD")
+    } else if line.contains('£') || has_code("GBP") {
+        Some("GBP")
+    } else {
+        None
+    }
+}
+
+/// The purchase date: of all dates on the receipt the one closest to a "Datum"/"Date" label,
+/// or the first one if there is no label. Expiry dates of coupons and the like come second.
+fn find_receipt_date(lines: &[OcrLine], pattern: &Regex) -> Option<NaiveDate> {
+    let dates: Vec<(usize, NaiveDate)> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(index, line)| detect_date(&line.text, pattern).map(|date| (index, date)))
+        .collect();
+    let labels: Vec<usize> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| {
+            let text = line.text.to_lowercase();
+            text.contains("datum") || text.contains("date")
+        })
+        .map(|(index, _)| index)
+        .collect();
+
+    // min_by_key keeps the first of equally close dates
+    dates
+        .iter()
+        .min_by_key(|(index, _)| labels.iter().map(|label| label.abs_diff(*index)).min().unwrap_or(0))
+        .map(|(_, date)| *date)
+}
+
+/// First valid date on the line. Dotted and slashed dates are read day first as on European
+/// receipts, falling back to month first when the day can't be one ("03/25/2024").
+/// Two-digit years are taken as 20xx.
+fn detect_date(line: &str, pattern: &Regex) -> Option<NaiveDate> {
+    pattern.captures_iter(line).find_map(|captures| {
+        let number = |i: usize| captures.get(i).and_then(|m| m.as_str().parse::<u32>().ok());
+        if let (Some(year), Some(month), Some(day)) = (number(4), number(5), number(6)) {
+            return NaiveDate::from_ymd_opt(year as i32, month, day);
+        }
+
+        let (first, second, year) = (number(1)?, number(2)?, number(3)?);
+        let year = if year < 100 { 2000 + year } else { year } as i32;
+        NaiveDate::from_ymd_opt(year, second, first).or_else(|| NaiveDate::from_ymd_opt(year, first, second))
+    })
+}
+
+/// Chains recognized anywhere in the receipt header, whatever OCR noise surrounds them
+const KNOWN_STORES: &[&str] = &[
+    "REWE", "ALDI", "LIDL", "EDEKA", "NETTO", "PENNY", "KAUFLAND", "ROSSMANN", "NORMA",
+];
+
+/// Finds the store in the header: the up to three non-empty lines before the first price.
+/// A known chain on any of them wins ("*** REWE Markt GmbH ***" -> "REWE"), otherwise the
+/// first line with a few letters is taken as the name.
+fn detect_store(lines: &[OcrLine], price_token: &Regex) -> Option<String> {
+    let header: Vec<&str> = lines
+        .iter()
+        .map(|line| line.text.trim())
+        .filter(|line| !line.is_empty())
+        .take_while(|line| !price_token.is_match(line))
+        .take(3)
+        .collect();
+
+    let known = header.iter().find_map(|line| {
+        line.split(|c: char| !c.is_alphanumeric())
+            .map(str::to_uppercase)
+            .find_map(|word| KNOWN_STORES.iter().find(|store| **store == word))
+    });
+    if let Some(store) = known {
+        return Some(store.to_string());
+    }
+
+    header
+        .iter()
+        .map(|line| {
+            line.split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .trim_matches(|c: char| !c.is_alphanumeric())
+                .to_string()
+        })
+        .find(|line| line.chars().filter(|c| c.is_alphabetic()).count() >= 3)
+}
+
+/// Recognizes lines stating how the receipt was paid. Every word on the line must be a payment
+/// or label word, so a product like "Schoko Bar 1,99" isn't mistaken for a cash payment.
+fn detect_payment_method(line: &str) -> Option<PaymentMethod> {
+    const CARD_WORDS: &[&str] = &[
+        "karte", "kartenzahlung", "ec", "girocard", "kreditkarte", "kontaktlos", "card", "visa",
+        "mastercard", "maestro", "amex", "debit", "credit", "contactless",
+    ];
+    const CASH_WORDS: &[&str] = &["bar", "bargeld", "barzahlung", "cash"];
+    const LABEL_WORDS: &[&str] = &[
+        "zahlung", "zahlungsart", "bezahlt", "mit", "per", "gegeben", "payment", "paid", "by",
+        "eur", "euro", "usd",
+    ];
+
+    let line_lower = line.to_lowercase();
+    let words: Vec<&str> = line_lower
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let is_payment_word = |word: &&str| {
+        CARD_WORDS.contains(word) || CASH_WORDS.contains(word) || LABEL_WORDS.contains(word)
+    };
+    if words.is_empty() || !words.iter().all(is_payment_word) {
+        return None;
+    }
+
+    if words.iter().any(|word| CARD_WORDS.contains(word)) {
+        Some(PaymentMethod::Card)
+    } else if words.iter().any(|word| CASH_WORDS.contains(word)) {
+        Some(PaymentMethod::Cash)
+    } else {
+        None
+    }
+}
+
+fn parse_quantity(qty_str: &str) -> u32 {
+    // Handle OCR errors where "1x" becomes "Ix", "lx", etc.
+    match qty_str.to_lowercase().as_str() {
+        "ix" | "lx" | "i" | "l" => 1,
+        _ => qty_str.parse().unwrap_or(1)
+    }
+}
+
+/// Substrings marking headers, totals, taxes, payment details and other non-product lines.
+/// Store-specific words belong in a `--skip-words` file, see `skip-words.example.txt`.
+pub const DEFAULT_SKIP_KEYWORDS: &[&str] = &[
+    "total", "subtotal", "summe", "netto", "brutto", "mwst", "tax", "steuer", "change",
+    "wechselgeld", "receipt", "quittung", "rechnung", "datum", "date", "uhrzeit", "tel:",
+    "telefon", "adresse", "address", "vielen dank", "danke", "nr.", "nummer", "check:",
+    "authorization", "approval", "payment", "gratuity", "signature", "customer copy",
+    "thanks", "inkl", "gegeben", "euro0", "eur0", "cust:",
+];
+
+/// Non-product keywords that are also parts of product names ("Discard Tray", "Timer"), so they
+/// only skip a line as a whole word
+pub const DEFAULT_SKIP_WORDS: &[&str] = &["card", "time"];
+
+/// French non-product keywords, added for [`Locale::French`]
+pub const FRENCH_SKIP_KEYWORDS: &[&str] = &[
+    "sous-total", "tva", "merci", "à bientôt", "a bientot", "rendu", "monnaie", "espèces", "especes",
+    "carte bancaire", "ticket", "siret", "montant", "à payer", "a payer", "facture", "heure",
+];
+
+/// Spanish non-product keywords, added for [`Locale::Spanish`]. "IVA" is only skipped as a whole
+/// word, it is part of too many product names ("olivas", "activia")
+pub const SPANISH_SKIP_KEYWORDS: &[&str] = &[
+    "gracias", "cambio", "efectivo", "tarjeta", "importe", "entregado", "a pagar", "base imponible",
+    "factura", "ticket", "fecha", "hora:", "cif:", "nif:",
+];
+
+/// Receipt languages with non-product keywords of their own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Locale {
+    German,
+    English,
+    French,
+    Spanish,
+}
+
+impl Locale {
+    /// The locale of a Tesseract language code ("deu", "fra") or a two-letter code ("de", "fr")
+    pub fn from_language(code: &str) -> Option<Locale> {
+        match code.trim().to_lowercase().as_str() {
+            "deu" | "de" => Some(Locale::German),
+            "eng" | "en" => Some(Locale::English),
+            "fra" | "fr" => Some(Locale::French),
+            "spa" | "es" => Some(Locale::Spanish),
+            _ => None,
+        }
+    }
+
+    /// Keywords skipped on top of the defaults, which already cover German and English
+    pub fn skip_keywords(self) -> &'static [&'static str] {
+        match self {
+            Locale::German | Locale::English => &[],
+            Locale::French => FRENCH_SKIP_KEYWORDS,
+            Locale::Spanish => SPANISH_SKIP_KEYWORDS,
+        }
+    }
+}
+
+/// Line starts that mark separators and barcode/reference numbers
+const SKIP_PREFIXES: &[&str] = &["#", "<<<", "888"];
+
+/// Built-in keywords of total, change and amount-due lines
+const TOTAL_KEYWORDS: &[&str] = &[
+    "total", "subtotal", "summe", "netto", "brutto", "change", "wechselgeld", "gegeben", "sous-total", "rendu",
+    "monnaie", "montant", "à payer", "a payer", "cambio", "importe", "entregado", "a pagar",
+];
+
+/// Built-in keywords of tax lines
+const TAX_KEYWORDS: &[&str] = &["mwst", "tax", "steuer", "inkl", "tva", "base imponible", "iva"];
+
+/// Built-in keywords of payment details
+const PAYMENT_KEYWORDS: &[&str] = &[
+    "payment", "card", "authorization", "approval", "gratuity", "signature", "customer copy", "espèces", "especes",
+    "carte bancaire", "efectivo", "tarjeta",
+];
+
+/// Why [`SkipMatcher::matches`] took a line for something other than a product. Every reason
+/// but `NumericOnly` holds the rule that matched, as printed by [`SkipReason::rule`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Totals, subtotals and change ("Summe", "Wechselgeld")
+    TotalLine(String),
+    /// Taxes ("MwSt", "TVA") and other lines with a percentage
+    TaxLine(String),
+    /// Payment details ("Card", "Authorization")
+    PaymentLine(String),
+    /// Store address, date, thanks and the other built-in header and footer keywords
+    Header(String),
+    /// Separator rows and barcode or reference numbers ("#####", "888...")
+    Separator(String),
+    /// Nothing but digits
+    NumericOnly,
+    /// The user's own rules: `--skip-words` keywords that aren't built in and `--skip-regex`
+    StoreSpecific(String),
+}
+
+impl SkipReason {
+    /// Short name of the reason: "total", "tax", "payment", "header", "separator", "numeric-only"
+    /// or "store-specific"
+    pub fn category(&self) -> &'static str {
+        match self {
+            SkipReason::TotalLine(_) => "total",
+            SkipReason::TaxLine(_) => "tax",
+            SkipReason::PaymentLine(_) => "payment",
+            SkipReason::Header(_) => "header",
+            SkipReason::Separator(_) => "separator",
+            SkipReason::NumericOnly => "numeric-only",
+            SkipReason::StoreSpecific(_) => "store-specific",
+        }
+    }
+
+    /// The rule that matched: `keyword "summe"`, `prefix "#"`, `--skip-regex "^TA-Nr"`
+    pub fn rule(&self) -> &str {
+        match self {
+            SkipReason::TotalLine(rule)
+            | SkipReason::TaxLine(rule)
+            | SkipReason::PaymentLine(rule)
+            | SkipReason::Header(rule)
+            | SkipReason::Separator(rule)
+            | SkipReason::StoreSpecific(rule) => rule,
+            SkipReason::NumericOnly => "numbers only",
+        }
+    }
+
+    /// The reason for a line containing `keyword`, described as `rule`
+    fn for_keyword(keyword: &str, rule: String) -> SkipReason {
+        let keyword = keyword.to_lowercase();
+        let is = |keywords: &[&str]| keywords.contains(&keyword.as_str());
+        if is(TOTAL_KEYWORDS) {
+            SkipReason::TotalLine(rule)
+        } else if is(TAX_KEYWORDS) {
+            SkipReason::TaxLine(rule)
+        } else if is(PAYMENT_KEYWORDS) {
+            SkipReason::PaymentLine(rule)
+        } else if is(DEFAULT_SKIP_KEYWORDS)
+            || is(DEFAULT_SKIP_WORDS)
+            || is(FRENCH_SKIP_KEYWORDS)
+            || is(SPANISH_SKIP_KEYWORDS)
+        {
+            SkipReason::Header(rule)
+        } else {
+            SkipReason::StoreSpecific(rule)
+        }
+    }
+}
+
+/// Decides which OCR lines are not products
+#[derive(Clone, Debug)]
+pub struct SkipMatcher {
+    /// Lines containing any of these, ignoring case, are skipped
+    pub keywords: Vec<String>,
+    /// Lines containing any of these as a whole word, ignoring case, are skipped. For keywords
+    /// that are also part of product names
+    pub words: Vec<String>,
+    /// Extra patterns for non-product lines, checked after the keywords
+    pub patterns: Vec<Regex>,
+}
+
+impl Default for SkipMatcher {
+    fn default() -> Self {
+        SkipMatcher {
+            keywords: DEFAULT_SKIP_KEYWORDS.iter().map(|k| k.to_string()).collect(),
+            words: DEFAULT_SKIP_WORDS.iter().map(|w| w.to_string()).collect(),
+            patterns: Vec::new(),
+        }
+    }
+}
+
+impl SkipMatcher {
+    /// Adds the non-product keywords of `locale`; sets add up, so a German and French run skips
+    /// both languages' noise
+    pub fn add_locale(&mut self, locale: Locale) {
+        self.keywords.extend(locale.skip_keywords().iter().map(|k| k.to_string()));
+        if locale == Locale::Spanish {
+            self.words.push("iva".to_string());
+        }
+    }
+
+    /// Returns why `line` is a non-product line, or `None` if it should be parsed
+    pub fn matches(&self, line: &str) -> Option<SkipReason> {
+        let line_lower = line.to_lowercase();
+
+        let keyword = self
+            .keywords
+            .iter()
+            .find(|keyword| line_lower.contains(&keyword.to_lowercase()));
+        if let Some(keyword) = keyword {
+            return Some(SkipReason::for_keyword(keyword, format!("keyword \"{}\"", keyword)));
+        }
+        let word = self
+            .words
+            .iter()
+            .find(|word| line_lower.split(|c: char| !c.is_alphanumeric()).any(|w| w == word.to_lowercase()));
+        if let Some(word) = word {
+            return Some(SkipReason::for_keyword(word, format!("word \"{}\"", word)));
+        }
+        if let Some(prefix) = SKIP_PREFIXES.iter().find(|prefix| line_lower.starts_with(*prefix)) {
+            return Some(SkipReason::Separator(format!("prefix \"{}\"", prefix)));
+        }
+        // Skip percentage lines, VAT rates for the most part
+        if line_lower.contains('%') {
+            return Some(SkipReason::TaxLine("percentage".to_string()));
+        }
+        // Skip lines that are just numbers
+        if line.chars().all(|c| c.is_numeric() || c.is_whitespace()) {
+            return Some(SkipReason::NumericOnly);
+        }
+        // User-supplied --skip-regex patterns
+        self.patterns
+            .iter()
+            .find(|pattern| pattern.is_match(line))
+            .map(|pattern| SkipReason::StoreSpecific(format!("--skip-regex \"{}\"", pattern.as_str())))
+    }
+}
+
+/// Reads a skip-words file: one substring per line, blank lines and lines starting with "#"
+/// are ignored
+pub fn parse_skip_words(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Compiles a `--skip-regex` pattern. A pattern that matches an empty line would skip every
+/// line of every receipt, so it is rejected along with invalid syntax
+pub fn parse_skip_regex(pattern: &str) -> Result<Regex> {
+    let regex = Regex::new(pattern)?;
+    if regex.is_match("") {
+        anyhow::bail!("\"{}\" matches an empty line, so it would skip every line", pattern);
+    }
+    Ok(regex)
+}
+
+/// Canonical product names for known aliases, so "äpfel" and "apfel" or "coca-cola 0,5l" and
+/// "coca cola" always end up as one product instead of depending on the fuzzy score
+#[derive(Clone, Debug, Default)]
+pub struct Synonyms {
+    /// Cleaned alias to the canonical name, tidied but in the casing of the file
+    canonical_names: HashMap<String, String>,
+}
+
+impl Synonyms {
+    /// Reads a synonyms file: one "canonical = alias1, alias2" rule per line, blank lines and
+    /// lines starting with "#" are ignored. A comma between two digits ("0,5l") is part of the
+    /// alias. Names are compared after `clean_product_name`, so case and punctuation don't
+    /// matter. An alias listed for two canonical names in the same file is an error.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut canonical_names: HashMap<String, String> = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((canonical, aliases)) = line.split_once('=') else {
+                anyhow::bail!("line {}: expected \"canonical = alias1, alias2\", got \"{}\"", index + 1, line);
+            };
+            let canonical = tidy_product_name(canonical);
+            if canonical.is_empty() {
+                anyhow::bail!("line {}: the canonical name is empty", index + 1);
+            }
+
+            for alias in split_aliases(aliases).map(clean_product_name).filter(|alias| !alias.is_empty()) {
+                match canonical_names.get(&alias) {
+                    Some(existing) if clean_product_name(existing) != clean_product_name(&canonical) => anyhow::bail!(
+                        "line {}: \"{}\" is already an alias of \"{}\"",
+                        index + 1,
+                        alias,
+                        existing
+                    ),
+                    _ => canonical_names.insert(alias, canonical.clone()),
+                };
+            }
+        }
+        Ok(Synonyms { canonical_names })
+    }
+
+    /// Adds the rules of a later file: where both list the same alias, `other`'s canonical name wins
+    pub fn merge(&mut self, other: Synonyms) {
+        self.canonical_names.extend(other.canonical_names);
+    }
+
+    /// The canonical name for a cleaned product name, if it is a listed alias. It keeps the
+    /// casing of the synonyms file, see [`tidy_product_name`]
+    pub fn canonical(&self, name: &str) -> Option<&str> {
+        self.canonical_names.get(name).map(String::as_str)
+    }
+}
+
+/// Splits a comma-separated alias or keyword list, except at decimal commas: "coca-cola 0,5l,
+/// coke" holds two aliases
+pub(crate) fn split_aliases(aliases: &str) -> impl Iterator<Item = &str> {
+    let chars: Vec<(usize, char)> = aliases.char_indices().collect();
+    let mut bounds = Vec::new();
+    let mut start = 0;
+    for (i, &(offset, c)) in chars.iter().enumerate() {
+        let is_decimal = i > 0
+            && chars[i - 1].1.is_ascii_digit()
+            && chars.get(i + 1).is_some_and(|(_, next)| next.is_ascii_digit());
+        if c == ',' && !is_decimal {
+            bounds.push((start, offset));
+            start = offset + 1;
+        }
+    }
+    bounds.push((start, aliases.len()));
+    bounds.into_iter().map(move |(start, end)| &aliases[start..end])
+}
+
+/// Parses an amount with either a decimal comma ("1,19") or a decimal point ("1.19"), with or
+/// without thousands separators ("1.299,00", "1,234.56"). When both separators appear the last
+/// one is the decimal separator; a lone dot followed by three digits ("1.299") separates
+/// thousands
+pub fn parse_european_price(price_str: &str) -> Result<f64, std::num::ParseFloatError> {
+    // Whole-euro shorthand with a dash for the cents: "3.-", "3,-", "3,–", "1.299,-"
+    if let Some(rest) = ["-", "–"].iter().find_map(|dash| price_str.strip_suffix(dash)) {
+        if let Some(euros) = rest.strip_suffix(',') {
+            return parse_european_price(&format!("{},00", euros));
+        }
+        if let Some(euros) = rest.strip_suffix('.') {
+            return parse_european_price(&format!("{}.00", euros));
+        }
+    }
+
+    match (price_str.rfind(','), price_str.rfind('.')) {
+        (Some(comma), Some(dot)) => {
+            let (whole, fraction) = price_str.split_at(comma.max(dot));
+            format!("{}.{}", whole.replace([',', '.'], ""), &fraction[1..]).parse::<f64>()
+        }
+        // European format: replace comma with dot
+        (Some(_), None) => price_str.replace(',', ".").parse::<f64>(),
+        (None, Some(dot)) if price_str.len() - dot - 1 == 3 => price_str.replace('.', "").parse::<f64>(),
+        // US format: parse directly
+        _ => price_str.parse::<f64>(),
+    }
+}
+
+/// Parses a quantity such as a weight or a percentage, where the separator is always decimal:
+/// "0,384", "1.250"
+fn parse_decimal(number: &str) -> Result<f64, std::num::ParseFloatError> {
+    number.replace(',', ".").parse::<f64>()
+}
+
+/// Normalizes a product name for matching: lowercase, alphanumeric words separated by single spaces
+pub fn clean_product_name(name: &str) -> String {
+    strip_punctuation(&name.trim().to_lowercase())
+}
+
+/// [`clean_product_name`] without the lowercasing, for displaying names as printed with
+/// `--preserve-case`: "  Löwenbräu  Original*" becomes "Löwenbräu Original"
+pub fn tidy_product_name(name: &str) -> String {
+    strip_punctuation(name.trim())
+}
+
+/// Alphanumeric words of `name` separated by single spaces. A decimal separator between digits
+/// stays as a comma, so "Wasser 0.5 l" keeps its amount as "wasser 0,5 l"
+fn strip_punctuation(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    chars
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &c)| {
+            let between_digits =
+                i > 0 && chars[i - 1].is_ascii_digit() && chars.get(i + 1).is_some_and(char::is_ascii_digit);
+            match c {
+                '.' | ',' if between_digits => Some(','),
+                // Keep German umlauts and special characters
+                c if c.is_alphanumeric() || c.is_whitespace() || "äöüßÄÖÜ".contains(c) => Some(c),
+                _ => None,
+            }
+        })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,508 @@
+//! Preparing receipt images for OCR: grayscale, cropping, rescaling, deskewing, sharpening and
+//! binarization.
+
+use crate::split_source;
+use image::imageops::FilterType;
+use image::math::Rect;
+use image::{DynamicImage, ImageBuffer, Luma};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// How the grayscale image is prepared for Tesseract
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Binarization {
+    /// Gamma correction, then stretch the contrast around mid-gray
+    #[default]
+    Contrast,
+    /// Pure black and white at the global Otsu threshold, for faded thermal receipts
+    Otsu,
+    /// Pure black and white against the mean of each pixel's neighborhood, for photos with a
+    /// shadow or uneven lighting across the receipt
+    Adaptive,
+    /// Leave the grayscale image as it is
+    None,
+}
+
+/// Contrast factor of [`Binarization::Contrast`] unless configured otherwise
+pub const DEFAULT_CONTRAST: f32 = 1.5;
+
+/// Gamma of [`Binarization::Contrast`] unless configured otherwise; 1.0 leaves the image as it is
+pub const DEFAULT_GAMMA: f32 = 1.0;
+
+/// Side of the square neighborhood of [`Binarization::Adaptive`] unless configured otherwise; a
+/// few text lines high at OCR resolution
+pub const DEFAULT_ADAPTIVE_WINDOW: u32 = 31;
+
+/// How much darker than its neighborhood mean a pixel has to be to turn black with
+/// [`Binarization::Adaptive`]. Keeps the paper grain of blank areas from becoming noise
+const ADAPTIVE_OFFSET: f64 = 10.0;
+
+/// Radius (standard deviation, in pixels) of the blur the unsharp mask subtracts; about the
+/// width of a stroke in receipt text at OCR resolution
+const SHARPEN_SIGMA: f32 = 1.5;
+
+/// Settings that control how images are prepared for OCR
+#[derive(Clone, Copy, Debug)]
+pub struct PreprocessOptions {
+    /// Crop the photo to the receipt when its outline can be found
+    pub autocrop: bool,
+    /// Rescale so the text height approaches what it would be at this DPI
+    pub target_dpi: Option<u32>,
+    /// Straighten tilted photos before OCR; costs a few projections of the image
+    pub deskew: bool,
+    pub binarization: Binarization,
+    /// Factor the distance from mid-gray is multiplied by with [`Binarization::Contrast`]
+    pub contrast: f32,
+    /// Gamma applied before the contrast step; above 1 brightens, below 1 darkens the midtones
+    pub gamma: f32,
+    /// Strength of the unsharp mask applied before contrast or binarization, 0 for none
+    pub sharpen: f32,
+    /// Side in pixels of the neighborhood [`Binarization::Adaptive`] compares each pixel with
+    pub adaptive_window: u32,
+}
+
+impl Default for PreprocessOptions {
+    fn default() -> Self {
+        PreprocessOptions {
+            autocrop: false,
+            target_dpi: None,
+            deskew: false,
+            binarization: Binarization::default(),
+            contrast: DEFAULT_CONTRAST,
+            gamma: DEFAULT_GAMMA,
+            sharpen: 0.0,
+            adaptive_window: DEFAULT_ADAPTIVE_WINDOW,
+        }
+    }
+}
+
+/// Prepares a receipt image for OCR: grayscale, optional rescaling towards `target_dpi` and
+/// a contrast boost
+pub fn preprocess_image(img: DynamicImage, target_dpi: Option<u32>) -> DynamicImage {
+    preprocess_image_with_options(img, &PreprocessOptions { target_dpi, ..PreprocessOptions::default() })
+}
+
+/// Prepares a receipt image for OCR: grayscale, optional cropping, rescaling, deskewing and
+/// sharpening, then contrast or binarization
+pub fn preprocess_image_with_options(img: DynamicImage, options: &PreprocessOptions) -> DynamicImage {
+    // Convert to grayscale
+    let mut gray = img.to_luma8();
+
+    // The desk around a receipt only gives Tesseract things to misread, and skews the estimates below
+    if options.autocrop
+        && let Some(region) = find_receipt_region(&gray)
+    {
+        gray = image::imageops::crop_imm(&gray, region.x, region.y, region.width, region.height).to_image();
+    }
+
+    // Bring the text to a size Tesseract reads reliably
+    if let Some(dpi) = options.target_dpi {
+        gray = scale_to_target_dpi(gray, dpi);
+    }
+
+    if options.deskew {
+        let angle = estimate_skew_angle(&gray);
+        gray = rotate_about_center(&gray, angle);
+    }
+
+    // Out-of-focus phone photos: sharpen before the contrast step amplifies the blur
+    gray = sharpen(gray, options.sharpen);
+
+    let enhanced = match options.binarization {
+        Binarization::Contrast => adjust(gray, options.contrast, options.gamma),
+        Binarization::Otsu => {
+            let threshold = otsu_threshold(&gray);
+            binarize(gray, threshold)
+        }
+        Binarization::Adaptive => adaptive_threshold(&gray, options.adaptive_window),
+        Binarization::None => gray,
+    };
+
+    DynamicImage::ImageLuma8(enhanced)
+}
+
+/// The receipt outline is searched on a copy no larger than this
+const AUTOCROP_SIZE: u32 = 400;
+
+/// Step in gray levels across a boundary that counts as an edge. Paper against a desk is a much
+/// bigger step; this only has to ignore wood grain and fabric
+const EDGE_THRESHOLD: f32 = 24.0;
+
+/// Share of each side of the candidate rectangle that has to lie on the outline before the crop is
+/// trusted
+const MIN_SIDE_COVERAGE: f32 = 0.6;
+
+/// Finds the receipt in a photo of it lying on a darker or lighter surface: the bounding box of the
+/// connected edges (Sobel on a blurred, shrunken copy) spanning the largest area. It is only
+/// returned if the edges run along all four sides of the box, so an outline cut off by the photo,
+/// a receipt tilted by more than a few degrees or a cluttered background give `None` rather than a
+/// wrong crop. So does a receipt filling nearly the whole photo, which leaves nothing to crop. The
+/// box is shrunk slightly so the outline itself is cut away.
+pub fn find_receipt_region(img: &ImageBuffer<Luma<u8>, Vec<u8>>) -> Option<Rect> {
+    let scale = (AUTOCROP_SIZE as f32 / img.width().max(img.height()) as f32).min(1.0);
+    let small = if scale < 1.0 {
+        let width = ((img.width() as f32 * scale).round() as u32).max(1);
+        let height = ((img.height() as f32 * scale).round() as u32).max(1);
+        image::imageops::resize(img, width, height, FilterType::Triangle)
+    } else {
+        img.clone()
+    };
+    let small = image::imageops::blur(&small, 1.0);
+    let (width, height) = (small.width() as usize, small.height() as usize);
+    if width < 3 || height < 3 {
+        return None;
+    }
+
+    // Sobel gradient magnitude, scaled back to gray levels; the outermost pixels are never edges
+    let mut edges = vec![false; width * height];
+    let value = |x: usize, y: usize| small.get_pixel(x as u32, y as u32)[0] as f32;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let gx = value(x + 1, y - 1) + 2.0 * value(x + 1, y) + value(x + 1, y + 1)
+                - value(x - 1, y - 1)
+                - 2.0 * value(x - 1, y)
+                - value(x - 1, y + 1);
+            let gy = value(x - 1, y + 1) + 2.0 * value(x, y + 1) + value(x + 1, y + 1)
+                - value(x - 1, y - 1)
+                - 2.0 * value(x, y - 1)
+                - value(x + 1, y - 1);
+            edges[y * width + x] = gx.hypot(gy) / 4.0 >= EDGE_THRESHOLD;
+        }
+    }
+
+    // Label the connected edges (8-neighborhood), keeping the one whose bounding box is largest
+    let mut labels = vec![0u32; width * height];
+    let mut best: Option<(u32, [usize; 4])> = None;
+    let mut label = 0;
+    for start in 0..width * height {
+        if !edges[start] || labels[start] != 0 {
+            continue;
+        }
+        label += 1;
+        labels[start] = label;
+        let mut stack = vec![start];
+        let (mut left, mut top, mut right, mut bottom) = (width, height, 0, 0);
+        while let Some(index) = stack.pop() {
+            let (x, y) = (index % width, index / width);
+            (left, top, right, bottom) = (left.min(x), top.min(y), right.max(x), bottom.max(y));
+            for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+                for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                    let neighbor = ny * width + nx;
+                    if edges[neighbor] && labels[neighbor] == 0 {
+                        labels[neighbor] = label;
+                        stack.push(neighbor);
+                    }
+                }
+            }
+        }
+        let area = |[left, top, right, bottom]: [usize; 4]| (right - left + 1) * (bottom - top + 1);
+        if best.is_none_or(|(_, bounds)| area([left, top, right, bottom]) > area(bounds)) {
+            best = Some((label, [left, top, right, bottom]));
+        }
+    }
+
+    let (label, [left, top, right, bottom]) = best?;
+    let area = ((right - left + 1) * (bottom - top + 1)) as f32 / (width * height) as f32;
+    if !(0.1..=0.9).contains(&area) {
+        return None;
+    }
+
+    // How much of a side has outline pixels within `band` of it, measured along the side
+    let band = (width.min(height) / 100).max(2);
+    let covered = |along: std::ops::RangeInclusive<usize>, across: std::ops::Range<usize>, horizontal: bool| {
+        let length = along.clone().count() as f32;
+        let hits = along
+            .filter(|&i| {
+                across.clone().any(|j| {
+                    let (x, y) = if horizontal { (i, j) } else { (j, i) };
+                    labels[y * width + x] == label
+                })
+            })
+            .count();
+        hits as f32 / length
+    };
+    let sides = [
+        covered(left..=right, top..(top + band).min(bottom), true),
+        covered(left..=right, bottom.saturating_sub(band).max(top) + 1..bottom + 1, true),
+        covered(top..=bottom, left..(left + band).min(right), false),
+        covered(top..=bottom, right.saturating_sub(band).max(left) + 1..right + 1, false),
+    ];
+    if sides.iter().any(|&coverage| coverage < MIN_SIDE_COVERAGE) {
+        return None;
+    }
+
+    // Back to full size, inside the outline
+    let to_full = |value: usize| ((value as f32 / scale).round() as u32).min(img.width().max(img.height()));
+    let (x, y) = (to_full(left + band).min(img.width() - 1), to_full(top + band).min(img.height() - 1));
+    let right = to_full(right.saturating_sub(band)).clamp(x + 1, img.width());
+    let bottom = to_full(bottom.saturating_sub(band)).clamp(y + 1, img.height());
+    Some(Rect { x, y, width: right - x, height: bottom - y })
+}
+
+/// Largest tilt, in degrees either way, that deskewing looks for
+const MAX_SKEW_DEGREES: f32 = 15.0;
+
+/// Skew is estimated on a copy no larger than this, which is plenty to find the text lines
+const SKEW_ESTIMATE_SIZE: u32 = 800;
+
+/// Estimates by how many degrees the text lines slope downwards to the right (negative if they
+/// rise). Dark pixels are projected onto the vertical axis for each candidate angle; at the right
+/// angle the text lines and the gaps between them give the sharpest profile.
+pub fn estimate_skew_angle(img: &ImageBuffer<Luma<u8>, Vec<u8>>) -> f32 {
+    let scale = (SKEW_ESTIMATE_SIZE as f32 / img.width().max(img.height()) as f32).min(1.0);
+    let small = if scale < 1.0 {
+        let width = ((img.width() as f32 * scale).round() as u32).max(1);
+        let height = ((img.height() as f32 * scale).round() as u32).max(1);
+        image::imageops::resize(img, width, height, FilterType::Triangle)
+    } else {
+        img.clone()
+    };
+
+    let threshold = otsu_threshold(&small);
+    let ink: Vec<(f32, f32)> = small
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel[0] <= threshold)
+        .map(|(x, y, _)| (x as f32, y as f32))
+        .collect();
+    // Blank pages and mostly dark photos have no text lines to go by
+    if ink.is_empty() || ink.len() > small.pixels().len() / 2 {
+        return 0.0;
+    }
+
+    let offset = small.width() as f32;
+    let bins = (small.height() + 2 * small.width()) as usize + 1;
+    let sharpness = |degrees: f32| {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let mut profile = vec![0u64; bins];
+        for (x, y) in &ink {
+            profile[(y * cos - x * sin + offset).round() as usize] += 1;
+        }
+        profile.iter().map(|count| count * count).sum::<u64>()
+    };
+    let best_in = |candidates: Vec<f32>| {
+        candidates
+            .into_iter()
+            .map(|degrees| (degrees, sharpness(degrees)))
+            .max_by_key(|(_, score)| *score)
+            .map_or(0.0, |(degrees, _)| degrees)
+    };
+
+    // Whole degrees first, then tenths around the best one
+    let max = MAX_SKEW_DEGREES as i32;
+    let coarse = best_in((-max..=max).map(|d| d as f32).collect());
+    best_in((-10..=10).map(|d| coarse + d as f32 / 10.0).collect())
+}
+
+/// Rotates the image so text sloping by `degrees` becomes horizontal, keeping its size.
+/// Corners that come from outside the original are filled with white.
+fn rotate_about_center(img: &ImageBuffer<Luma<u8>, Vec<u8>>, degrees: f32) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+    // Tesseract copes with a fraction of a degree, resampling would only blur
+    if degrees.abs() < 0.2 {
+        return img.clone();
+    }
+
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let center_x = (img.width() as f32 - 1.0) / 2.0;
+    let center_y = (img.height() as f32 - 1.0) / 2.0;
+    let max_x = img.width() as f32 - 1.0;
+    let max_y = img.height() as f32 - 1.0;
+
+    ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let dx = x as f32 - center_x;
+        let dy = y as f32 - center_y;
+        let source_x = center_x + dx * cos - dy * sin;
+        let source_y = center_y + dx * sin + dy * cos;
+        if !(0.0..=max_x).contains(&source_x) || !(0.0..=max_y).contains(&source_y) {
+            return Luma([255]);
+        }
+
+        // Bilinear interpolation between the four surrounding pixels
+        let (x0, y0) = (source_x.floor() as u32, source_y.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(img.width() - 1), (y0 + 1).min(img.height() - 1));
+        let (fx, fy) = (source_x - x0 as f32, source_y - y0 as f32);
+        let value = |x, y| img.get_pixel(x, y)[0] as f32;
+        let top = value(x0, y0) * (1.0 - fx) + value(x1, y0) * fx;
+        let bottom = value(x0, y1) * (1.0 - fx) + value(x1, y1) * fx;
+        Luma([(top * (1.0 - fy) + bottom * fy).round() as u8])
+    })
+}
+
+/// Typical height of a printed receipt text line (about 2.5 mm)
+const ASSUMED_TEXT_HEIGHT_INCHES: f32 = 0.1;
+
+/// Rescales the image so its estimated text height matches what it would be when scanned at
+/// `target_dpi`. Images whose text height can't be estimated are left untouched.
+fn scale_to_target_dpi(img: ImageBuffer<Luma<u8>, Vec<u8>>, target_dpi: u32) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+    let Some(text_height) = estimate_text_height(&img) else {
+        return img;
+    };
+
+    let wanted_height = target_dpi as f32 * ASSUMED_TEXT_HEIGHT_INCHES;
+    let scale = (wanted_height / text_height as f32).clamp(0.25, 4.0);
+    // Not worth resampling for small differences
+    if (scale - 1.0).abs() < 0.1 {
+        return img;
+    }
+
+    let width = ((img.width() as f32 * scale).round() as u32).max(1);
+    let height = ((img.height() as f32 * scale).round() as u32).max(1);
+    image::imageops::resize(&img, width, height, FilterType::CatmullRom)
+}
+
+/// Estimates the text height in pixels as the median height of the horizontal bands that
+/// contain dark pixels, i.e. the text lines
+fn estimate_text_height(img: &ImageBuffer<Luma<u8>, Vec<u8>>) -> Option<u32> {
+    let min_ink = (img.width() / 200).max(1);
+    let mut band_heights = Vec::new();
+    let mut current_band = 0;
+
+    for row in img.rows() {
+        let ink = row.filter(|pixel| pixel[0] < 128).count() as u32;
+        if ink >= min_ink {
+            current_band += 1;
+        } else {
+            // Bands of one or two rows are rules and specks, not text
+            if current_band > 2 {
+                band_heights.push(current_band);
+            }
+            current_band = 0;
+        }
+    }
+    if current_band > 2 {
+        band_heights.push(current_band);
+    }
+
+    if band_heights.is_empty() {
+        return None;
+    }
+    band_heights.sort_unstable();
+    Some(band_heights[band_heights.len() / 2])
+}
+
+/// Global threshold that best separates the gray levels into two classes (Otsu's method):
+/// the level maximizing the variance between the pixels at or below it and those above it
+pub fn otsu_threshold(img: &ImageBuffer<Luma<u8>, Vec<u8>>) -> u8 {
+    let mut histogram = [0u64; 256];
+    for pixel in img.pixels() {
+        histogram[pixel[0] as usize] += 1;
+    }
+
+    let total = img.pixels().len() as f64;
+    let weighted_total: f64 = histogram.iter().enumerate().map(|(level, &n)| level as f64 * n as f64).sum();
+
+    let mut best_threshold = 0;
+    let mut best_variance = 0.0;
+    let mut background = 0.0;
+    let mut weighted_background = 0.0;
+    for (level, &count) in histogram.iter().enumerate() {
+        background += count as f64;
+        if background == 0.0 {
+            continue;
+        }
+        let foreground = total - background;
+        if foreground == 0.0 {
+            break;
+        }
+        weighted_background += level as f64 * count as f64;
+
+        let mean_background = weighted_background / background;
+        let mean_foreground = (weighted_total - weighted_background) / foreground;
+        let variance = background * foreground * (mean_background - mean_foreground).powi(2);
+        if variance > best_variance {
+            best_variance = variance;
+            best_threshold = level as u8;
+        }
+    }
+
+    best_threshold
+}
+
+/// Turns pixels above `threshold` white and the rest black
+fn binarize(mut img: ImageBuffer<Luma<u8>, Vec<u8>>, threshold: u8) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+    for pixel in img.pixels_mut() {
+        pixel[0] = if pixel[0] > threshold { 255 } else { 0 };
+    }
+    img
+}
+
+/// Local thresholding: a pixel turns black when it is more than a small offset darker than the
+/// mean of the `window`×`window` square around it (cut off at the edges), white otherwise. Unlike
+/// a global threshold this follows a shadow or brightness gradient across the image. The means
+/// come from a summed-area table, so the cost doesn't depend on the window size.
+pub fn adaptive_threshold(img: &ImageBuffer<Luma<u8>, Vec<u8>>, window: u32) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    // sums[y * (width + 1) + x] is the sum of the pixels above and left of (x, y)
+    let mut sums = vec![0u64; (width + 1) * (height + 1)];
+    for y in 0..height {
+        let mut row_sum = 0;
+        for x in 0..width {
+            row_sum += img.get_pixel(x as u32, y as u32)[0] as u64;
+            sums[(y + 1) * (width + 1) + x + 1] = sums[y * (width + 1) + x + 1] + row_sum;
+        }
+    }
+
+    let half = (window / 2) as usize;
+    ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let (x, y) = (x as usize, y as usize);
+        let (left, top) = (x.saturating_sub(half), y.saturating_sub(half));
+        let (right, bottom) = ((x + half + 1).min(width), (y + half + 1).min(height));
+        let sum = sums[bottom * (width + 1) + right] + sums[top * (width + 1) + left]
+            - sums[top * (width + 1) + right]
+            - sums[bottom * (width + 1) + left];
+        let mean = sum as f64 / ((right - left) * (bottom - top)) as f64;
+        let value = img.get_pixel(x as u32, y as u32)[0] as f64;
+        Luma([if value < mean - ADAPTIVE_OFFSET { 0 } else { 255 }])
+    })
+}
+
+/// Unsharp mask: adds `amount` times the difference between the image and a Gaussian blur of
+/// it, which steepens the edges of blurred text. An amount of 0 leaves the image unchanged.
+pub fn sharpen(img: ImageBuffer<Luma<u8>, Vec<u8>>, amount: f32) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+    if amount <= 0.0 {
+        return img;
+    }
+    let blurred = image::imageops::blur(&img, SHARPEN_SIGMA);
+    ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let (original, blurred) = (img.get_pixel(x, y)[0] as f32, blurred.get_pixel(x, y)[0] as f32);
+        Luma([(original + amount * (original - blurred)).round().clamp(0.0, 255.0) as u8])
+    })
+}
+
+/// Gamma correction (`255 * (v / 255)^(1 / gamma)`) followed by a contrast stretch around
+/// mid-gray by `contrast`. Contrast and gamma 1.0 leave the image unchanged.
+pub fn adjust(mut img: ImageBuffer<Luma<u8>, Vec<u8>>, contrast: f32, gamma: f32) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+    // Every pixel with the same gray level ends up the same, so compute each level once
+    let levels: Vec<u8> = (0..=255u8)
+        .map(|level| {
+            let corrected = (255.0 * (level as f32 / 255.0).powf(1.0 / gamma)).round();
+            ((corrected - 128.0) * contrast + 128.0).clamp(0.0, 255.0) as u8
+        })
+        .collect();
+
+    for pixel in img.pixels_mut() {
+        pixel[0] = levels[pixel[0] as usize];
+    }
+
+    img
+}
+
+/// File name for the preprocessed copy of `source` kept by --keep-processed: "IMG_01-1a2b3c4d.png"
+/// for "IMG_01.jpg", "scan-page2-….png" for page 2 of "scan.pdf", "IMG_02-….png" for
+/// "backup.zip#2024/IMG_02.jpg". The suffix is the start of the SHA-256 of the whole source, so
+/// receipts with the same file name in different directories or archives don't overwrite each other
+pub fn processed_image_name(source: &Path) -> String {
+    let stem = |path: &Path| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let hash: String = Sha256::digest(source.to_string_lossy().as_bytes())[..4]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let (file, part) = split_source(source);
+    let name = match part {
+        Some(part) => match part.strip_prefix("page=") {
+            Some(page) => format!("{}-page{}", stem(&file), page),
+            None => stem(Path::new(&part)),
+        },
+        None => stem(&file),
+    };
+    format!("{}-{}.png", name, hash)
+}
@@ -0,0 +1,115 @@
+//! `--timings`: wall time spent on every image in preprocessing, OCR and parsing, printed to
+//! stderr as a table after the results.
+
+use prettytable::{format, Cell, Row, Table};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    /// `preprocess_image_with_options`
+    Preprocess,
+    /// Writing the preprocessed image and Tesseract reading it
+    Ocr,
+    /// `parse_receipt` on the recognized lines
+    Parse,
+    /// Everything done for the image, including orientation detection and retries
+    Total,
+}
+
+/// Time per stage for one image; retries add to the first pass
+#[derive(Clone, Copy, Debug, Default)]
+struct StageTimes {
+    preprocess: Duration,
+    ocr: Duration,
+    parse: Duration,
+    total: Duration,
+}
+
+impl StageTimes {
+    fn add(&mut self, other: &StageTimes) {
+        self.preprocess += other.preprocess;
+        self.ocr += other.ocr;
+        self.parse += other.parse;
+        self.total += other.total;
+    }
+}
+
+/// Times collected by the worker threads, per image
+#[derive(Debug, Default)]
+pub struct Timings {
+    images: Mutex<BTreeMap<PathBuf, StageTimes>>,
+}
+
+impl Timings {
+    fn record(&self, source: &Path, stage: Stage, elapsed: Duration) {
+        // A worker that panicked while holding the lock leaves consistent times behind
+        let mut images = self.images.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let times = images.entry(source.to_path_buf()).or_default();
+        let slot = match stage {
+            Stage::Preprocess => &mut times.preprocess,
+            Stage::Ocr => &mut times.ocr,
+            Stage::Parse => &mut times.parse,
+            Stage::Total => &mut times.total,
+        };
+        *slot += elapsed;
+    }
+
+    /// Prints a row per image, the sums, and each stage's share of the summed totals. `wall_time`
+    /// is how long the scan took; with several workers it is less than the summed totals
+    pub fn display(&self, wall_time: Duration) {
+        let images = self.images.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if images.is_empty() {
+            return;
+        }
+
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_BORDERS_ONLY);
+        table.set_titles(Row::new(vec![
+            Cell::new("Image"),
+            Cell::new("Preprocess").style_spec("r"),
+            Cell::new("OCR").style_spec("r"),
+            Cell::new("Parse").style_spec("r"),
+            Cell::new("Total").style_spec("r"),
+        ]));
+        let row = |label: &str, values: [String; 4]| {
+            let mut cells = vec![Cell::new(label)];
+            cells.extend(values.iter().map(|value| Cell::new(value).style_spec("r")));
+            Row::new(cells)
+        };
+        let seconds = |times: &StageTimes| {
+            [times.preprocess, times.ocr, times.parse, times.total].map(|time| format!("{:.3}s", time.as_secs_f64()))
+        };
+
+        let mut sum = StageTimes::default();
+        for (source, times) in images.iter() {
+            table.add_row(row(&source.display().to_string(), seconds(times)));
+            sum.add(times);
+        }
+        table.add_row(row("TOTAL", seconds(&sum)));
+        let total = sum.total.as_secs_f64();
+        if total > 0.0 {
+            let shares = [sum.preprocess, sum.ocr, sum.parse, sum.total]
+                .map(|time| format!("{:.1}%", time.as_secs_f64() / total * 100.0));
+            table.add_row(row("Share", shares));
+        }
+
+        eprintln!("\nTimings");
+        // Diagnostics, like the log; a broken stderr isn't worth failing the run over
+        let _ = table.print(&mut std::io::stderr());
+        eprintln!("Wall time: {:.3}s for {} images", wall_time.as_secs_f64(), images.len());
+    }
+}
+
+/// Runs `work`, adding the time it took to `stage` of `source` when --timings is on
+pub fn time<T>(timings: Option<&Timings>, source: &Path, stage: Stage, work: impl FnOnce() -> T) -> T {
+    let Some(timings) = timings else {
+        return work();
+    };
+    let started = Instant::now();
+    let result = work();
+    timings.record(source, stage, started.elapsed());
+    result
+}
@@ -0,0 +1,125 @@
+//! `--watch`: keeps OCRing receipts as a scanner drops them into the input directory and prints
+//! the updated tables after every batch.
+
+use crate::output::join_paths;
+use crate::{extract_receipts_from_file, record_in_db, DirectoryScan, ScanOptions, TEMP_FILE_PREFIX};
+use anyhow::{Context, Result};
+use log::{error, info};
+use notify::{EventKind, RecursiveMode, Watcher};
+use receipt_analyzer::{has_receipt_extension, split_source, ParseOptions, Receipt};
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long a file has to go without events before it is read. Scanners write an image in
+/// several chunks, each of which is reported as a change
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// How often the loop wakes up to look for settled files and Ctrl-C
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Watches the `inputs` directories until Ctrl-C, adding every new receipt to `scan` and calling `display` with
+/// all receipts so far after each batch. Receipts are also recorded in `db` if given. Files
+/// already in `scan` are not read again when they change.
+pub fn watch_directory(
+    inputs: &[PathBuf],
+    scan: &mut DirectoryScan,
+    mut db: Option<(&mut Connection, &Path)>,
+    parse_options: &Arc<ParseOptions>,
+    scan_options: &Arc<ScanOptions>,
+    display: impl Fn(&[Receipt]) -> Result<()>,
+) -> Result<()> {
+    // Ctrl-C only raises a flag, so the image being read is finished and the table flushed
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = Arc::clone(&stop);
+    ctrlc::set_handler(move || handler_stop.store(true, Ordering::SeqCst))
+        .context("Failed to install the Ctrl-C handler")?;
+
+    let (sender, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).context("Failed to start watching for new files")?;
+    let mode = if scan_options.files.recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+    for input in inputs {
+        watcher
+            .watch(input, mode)
+            .with_context(|| format!("Failed to watch {}", input.display()))?;
+    }
+    println!("\nWatching {} for new receipts, press Ctrl-C to stop", join_paths(inputs));
+
+    // Compared canonicalized: events carry absolute paths, scanned receipts paths below the inputs
+    let mut processed: HashSet<PathBuf> =
+        scan.receipts.iter().map(|receipt| canonical(&split_source(&receipt.source).0)).collect();
+    // --keep-processed may point inside the watched directory; its images aren't receipts
+    let keep_processed = scan_options.keep_processed.as_deref().map(canonical);
+    // Files with recent events and when the last one arrived
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    loop {
+        let stopping = stop.load(Ordering::SeqCst);
+        // On Ctrl-C, files still being written are read as they are rather than dropped
+        let ready: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, last_event)| stopping || last_event.elapsed() >= SETTLE_TIME)
+            .map(|(path, _)| path.clone())
+            .collect();
+        if !ready.is_empty() {
+            let known = scan.receipts.len();
+            for path in ready {
+                pending.remove(&path);
+                // Scanners that write under a temporary name rename or delete it afterwards
+                if !path.is_file() || !processed.insert(canonical(&path)) {
+                    continue;
+                }
+                for result in extract_receipts_from_file(&path, parse_options, scan_options) {
+                    scan.add_result(result, scan_options)?;
+                }
+            }
+
+            let added = &scan.receipts[known..];
+            if !added.is_empty() {
+                if let Some((conn, db_path)) = &mut db {
+                    record_in_db(conn, db_path, added)?;
+                }
+                display(&scan.receipts)?;
+            }
+        }
+
+        if stopping {
+            break;
+        }
+        match events.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    let new_receipts = event.paths.into_iter().filter(|path| {
+                        is_new_receipt(path, &scan_options.files.extensions)
+                            && !keep_processed.as_ref().is_some_and(|dir| path.starts_with(dir))
+                    });
+                    for path in new_receipts {
+                        pending.insert(path, Instant::now());
+                    }
+                }
+            }
+            Ok(Err(e)) => error!("Failed to watch {}: {}", join_paths(inputs), e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    info!("Stopped watching after {}", scan.summary());
+    Ok(())
+}
+
+/// Files with a receipt extension, except our own preprocessed copies and hidden files such as
+/// partial downloads
+fn is_new_receipt(path: &Path, extensions: &[String]) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    has_receipt_extension(path, extensions) && !name.starts_with(TEMP_FILE_PREFIX) && !name.starts_with('.')
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
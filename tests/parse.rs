@@ -0,0 +1,902 @@
+use receipt_analyzer::{
+    aggregate_products, category_totals, count_identical_items, find_receipt_files, parse_european_price,
+    parse_ocr_lines, parse_receipt, parse_receipt_from_text, parse_receipt_text, parse_receipt_text_with_options,
+    parse_skip_regex, parse_skip_words, parse_tesseract_tsv, parse_tesseract_tsv_spatial, process_receipts_iter,
+    round_price, serialize_cents, serialize_cents_map, serialize_optional_cents, stats, vat_rate, AggregateOptions,
+    Categories, FileSelection, JaroNameMatcher, Locale, NameMatcher, OcrLine, ParseOptions, PaymentMethod, Receipt,
+    ReceiptReader, Rounding, SkimNameMatcher, SkipMatcher, SkipReason, Stats, Synonyms, TokenSetNameMatcher, Weight,
+    UNNAMED_ITEM,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+fn parse(text: &str) -> Vec<(String, f64, u32)> {
+    parse_receipt_text(text)
+        .unwrap()
+        .into_iter()
+        .map(|p| (p.name, (p.price * 100.0).round() / 100.0, p.quantity))
+        .collect()
+}
+
+#[test]
+fn quantity_with_unit_price_and_line_total_keeps_total() {
+    assert_eq!(parse("4x Bier a 3,00 12,00"), vec![("bier".to_string(), 12.0, 4)]);
+}
+
+#[test]
+fn quantity_with_lone_price_multiplies_unit_price() {
+    assert_eq!(parse("2x Cola 5,00"), vec![("cola".to_string(), 10.0, 2)]);
+    assert_eq!(parse("4x Bier a 3,00"), vec![("bier".to_string(), 12.0, 4)]);
+    assert_eq!(parse("2x Kaffee à 2,50"), vec![("kaffee".to_string(), 5.0, 2)]);
+    assert_eq!(parse("3x Brezel x 0,80 2,40"), vec![("brezel".to_string(), 2.4, 3)]);
+}
+
+#[test]
+fn price_on_the_line_below_a_long_name() {
+    assert_eq!(
+        parse("Bio-Vollkornbrot geschnitten\n2,49\nMilch 1,19"),
+        vec![("biovollkornbrot geschnitten".to_string(), 2.49, 1), ("milch".to_string(), 1.19, 1)]
+    );
+    // A line with a price of its own keeps it, the bare amount below isn't attached to it
+    assert_eq!(parse("Milch 1,19\n2,49"), vec![("milch".to_string(), 1.19, 1)]);
+}
+
+#[test]
+fn prices_split_after_the_separator_are_rejoined() {
+    assert_eq!(parse("Kaffee 3,\n50"), vec![("kaffee".to_string(), 3.5, 1)]);
+    assert_eq!(parse("Kaffee 3,\n50\nMilch 1,19"), vec![("kaffee".to_string(), 3.5, 1), ("milch".to_string(), 1.19, 1)]);
+}
+
+#[test]
+fn unmatched_lines_are_collected() {
+    let options = ParseOptions { collect_unmatched: true, ..ParseOptions::default() };
+    let text = "Milch 1,19\nMwSt 19% 0,19\nKSSE 7 BON 4711\nBananen\n2 @ 0,50\nBrot 2,49";
+    let receipt = parse_receipt_from_text(text, &options).unwrap();
+    assert_eq!(receipt.items.len(), 3);
+    // The skipped VAT line and the name borrowed by "2 @ 0,50" are not listed
+    assert_eq!(receipt.unmatched, vec!["KSSE 7 BON 4711".to_string()]);
+
+    // Off by default
+    assert!(parse_receipt_from_text(text, &ParseOptions::default()).unwrap().unmatched.is_empty());
+}
+
+#[test]
+fn ocr_garbled_quantity_reads_as_one() {
+    assert_eq!(parse("Ix Brezel 1,19"), vec![("brezel".to_string(), 1.19, 1)]);
+}
+
+#[test]
+fn max_price_is_configurable() {
+    let options = ParseOptions { max_price: 2000.0, ..ParseOptions::default() };
+    let products = parse_receipt_text_with_options("Laptop 1299,00", &options).unwrap();
+    assert_eq!(products.len(), 1);
+    assert_eq!(products[0].name, "laptop");
+    assert_eq!(products[0].price, 1299.0);
+
+    // Dropped with the default bound of 1000
+    assert!(parse("Laptop 1299,00").is_empty());
+}
+
+#[test]
+fn price_bounds_are_inclusive() {
+    let options = ParseOptions { min_price: 1.0, max_price: 2.5, ..ParseOptions::default() };
+    let products = parse_receipt_text_with_options("Milch 1,00\nBrot 2,50\nKaffee 2,51", &options).unwrap();
+    let names: Vec<&str> = products.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, ["milch", "brot"]);
+}
+
+#[test]
+fn low_confidence_lines_are_dropped() {
+    let tsv = "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext\n\
+               4\t1\t1\t1\t1\t0\t10\t10\t200\t20\t-1\t\n\
+               5\t1\t1\t1\t1\t1\t10\t10\t80\t20\t96.5\tMilch\n\
+               5\t1\t1\t1\t1\t2\t150\t10\t60\t20\t91.5\t1,19\n\
+               5\t1\t1\t1\t2\t1\t10\t40\t80\t20\t31.0\tB7ot\n\
+               5\t1\t1\t1\t2\t2\t150\t40\t60\t20\t45.0\t2,49\n";
+    let lines = parse_tesseract_tsv(tsv);
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].text, "Milch 1,19");
+    assert_eq!(lines[0].confidence, 94.0);
+    assert_eq!(lines[1].confidence, 38.0);
+
+    // The default threshold of 0 keeps everything
+    assert_eq!(parse_ocr_lines(&lines, &ParseOptions::default()).unwrap().len(), 2);
+
+    let options = ParseOptions { min_confidence: 60.0, ..ParseOptions::default() };
+    let products = parse_ocr_lines(&lines, &options).unwrap();
+    assert_eq!(products.len(), 1);
+    assert_eq!(products[0].name, "milch");
+    assert_eq!(products[0].confidence, 94.0);
+}
+
+#[test]
+fn discounts_and_refunds_are_negative() {
+    assert_eq!(parse("Rabatt -1,00"), vec![("rabatt".to_string(), -1.0, 1)]);
+    assert_eq!(parse("-0,50 Pfandrückgabe"), vec![("pfandrückgabe".to_string(), -0.5, 1)]);
+    assert_eq!(parse("Leergut 0,75"), vec![("leergut".to_string(), -0.75, 1)]);
+    // The deposit itself is charged
+    assert_eq!(parse("Pfand 0,25"), vec![("pfand".to_string(), 0.25, 1)]);
+}
+
+#[test]
+fn a_dash_between_name_and_price_is_not_a_minus() {
+    assert_eq!(parse("Milch - 1,19"), vec![("milch".to_string(), 1.19, 1)]);
+    assert_eq!(parse("Milch -1,19"), vec![("milch".to_string(), -1.19, 1)]);
+}
+
+#[test]
+fn credit_markers_after_the_amount() {
+    assert_eq!(parse("Retoure 5,00-"), vec![("retoure".to_string(), -5.0, 1)]);
+    assert_eq!(parse("Retoure 5,00 CR"), vec![("retoure".to_string(), -5.0, 1)]);
+    assert_eq!(parse("Retoure 5,00 H"), vec![("retoure".to_string(), -5.0, 1)]);
+
+    let options = ParseOptions { credit_markers: vec!["CR".to_string()], ..ParseOptions::default() };
+    let products = parse_receipt_text_with_options("Retoure 5,00 H\nRetoure 5,00 CR", &options).unwrap();
+    let prices: Vec<f64> = products.iter().map(|p| p.price).collect();
+    assert_eq!(prices, [5.0, -5.0]);
+}
+
+#[test]
+fn percent_discounts_only_apply_to_the_item_right_above() {
+    assert_eq!(parse("Kaffee 10,00\n-20% Aktion"), vec![("kaffee".to_string(), 8.0, 1)]);
+    assert_eq!(parse("Kaffee 10,00\nRabatt -10%"), vec![("kaffee".to_string(), 9.0, 1)]);
+    // After a name the percentage is part of an item line whose price already has the discount
+    assert_eq!(parse("Joghurt -20% 1,99"), vec![("joghurt".to_string(), 1.99, 1)]);
+    assert_eq!(
+        parse("Kaffee 10,00\nJoghurt -20% 1,99"),
+        vec![("kaffee".to_string(), 10.0, 1), ("joghurt".to_string(), 1.99, 1)]
+    );
+
+    let options = ParseOptions { collect_unmatched: true, ..ParseOptions::default() };
+    let receipt = parse_receipt_from_text("Kaffee 10,00\nKSSE 7 BON 4711\n-20% Aktion", &options).unwrap();
+    assert_eq!(receipt.items[0].price, 10.0);
+    assert!(receipt.unmatched.contains(&"-20% Aktion".to_string()));
+}
+
+#[test]
+fn discount_reduces_grand_total() {
+    let products = parse_receipt_text("Milch 2,00\nRabatt -0,50").unwrap();
+    let aggregated = aggregate_products(products, &SkimNameMatcher::default(), &AggregateOptions::default());
+    let grand_total: f64 = aggregated.iter().map(|p| p.total).sum();
+    assert_eq!(grand_total, 1.5);
+}
+
+#[test]
+fn unit_price_averages_over_merged_quantities() {
+    let products = parse_receipt_text("2x Cola 3,00\nCola 1,80").unwrap();
+    let aggregated = aggregate_products(products, &SkimNameMatcher::default(), &AggregateOptions::default());
+    assert_eq!(aggregated.len(), 1);
+    assert_eq!(aggregated[0].count, 3);
+    assert!((aggregated[0].unit_price().unwrap() - 2.6).abs() < 1e-9);
+}
+
+#[test]
+fn appearances_count_items_not_units() {
+    let products = parse_receipt_text("2x Cola 3,00\nCola 1,80\nMilch 1,19").unwrap();
+    let aggregated = aggregate_products(products, &SkimNameMatcher::default(), &AggregateOptions::default());
+    let counts: Vec<(&str, u32, u32)> = aggregated.iter().map(|p| (p.name.as_str(), p.appearances, p.count)).collect();
+    assert_eq!(counts, [("cola", 2, 3), ("milch", 1, 1)]);
+}
+
+#[test]
+fn unit_price_range_covers_every_merged_item() {
+    let products = parse_receipt_text("2x Cola 3,00\nCola 1,80\nCola 2,20").unwrap();
+    let aggregated = aggregate_products(products, &SkimNameMatcher::default(), &AggregateOptions::default());
+    assert_eq!(aggregated.len(), 1);
+    assert_eq!((aggregated[0].min_unit_price, aggregated[0].max_unit_price), (1.8, 3.0));
+
+    let counted = count_identical_items(parse_receipt_text("Cola 1,80\nCola 1,80").unwrap());
+    assert_eq!((counted[0].count, counted[0].min_unit_price, counted[0].max_unit_price), (2, 1.8, 1.8));
+}
+
+#[test]
+fn spatial_layout_rebuilds_rows_from_word_positions() {
+    // Tesseract put the far-right prices in a block of their own, so its lines separate them from the names
+    let tsv = "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext\n\
+               5\t1\t1\t1\t1\t1\t10\t10\t80\t20\t90\tMilch\n\
+               5\t1\t1\t1\t2\t1\t10\t40\t60\t20\t90\tBrot\n\
+               5\t1\t2\t1\t1\t1\t400\t12\t50\t20\t80\t1,19\n\
+               5\t1\t2\t1\t2\t1\t400\t41\t50\t20\t80\t2,49\n\
+               5\t1\t3\t1\t1\t1\t10\t70\t30\t20\t90\t4x\n\
+               5\t1\t3\t1\t1\t2\t45\t70\t40\t20\t90\tBier\n\
+               5\t1\t3\t1\t1\t3\t200\t70\t10\t20\t90\ta\n\
+               5\t1\t3\t1\t1\t4\t215\t70\t50\t20\t90\t3,00\n\
+               5\t1\t3\t1\t1\t5\t400\t70\t50\t20\t90\t12,00\n\
+               5\t1\t4\t1\t1\t1\t10\t100\t60\t20\t90\tKäse\n\
+               5\t1\t4\t1\t1\t2\t120\t100\t50\t20\t90\t2,99\n\
+               5\t1\t4\t1\t1\t3\t250\t100\t60\t20\t90\tEier\n\
+               5\t1\t4\t1\t1\t4\t400\t100\t50\t20\t90\t1,89\n";
+
+    let regex_lines: Vec<String> = parse_tesseract_tsv(tsv).into_iter().map(|line| line.text).collect();
+    assert_eq!(regex_lines[..4], ["Milch", "Brot", "1,19", "2,49"]);
+
+    let lines = parse_tesseract_tsv_spatial(tsv).unwrap();
+    let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+    assert_eq!(texts, ["Milch 1,19", "Brot 2,49", "4x Bier a 3,00 12,00", "Käse 2,99", "Eier 1,89"]);
+    assert_eq!(lines[0].confidence, 85.0);
+
+    let products = parse_ocr_lines(&lines, &ParseOptions::default()).unwrap();
+    let names: Vec<&str> = products.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, ["milch", "brot", "bier", "käse", "eier"]);
+}
+
+#[test]
+fn identical_items_are_counted_not_merged() {
+    let products = parse_receipt_text("Apfel 1,50\nMilch 1,19\nApfel 1,50\n2x Apfel 1,50\nApfel 1,80").unwrap();
+    let counted: Vec<(String, f64, u32)> = count_identical_items(products)
+        .into_iter()
+        .map(|p| (p.name, (p.total * 100.0).round() / 100.0, p.count))
+        .collect();
+    assert_eq!(
+        counted,
+        vec![
+            ("apfel".to_string(), 6.0, 4),
+            ("apfel".to_string(), 1.8, 1),
+            ("milch".to_string(), 1.19, 1),
+        ]
+    );
+}
+
+#[test]
+fn stats_use_the_middle_of_the_sorted_prices() {
+    let summarize = |text: &str| {
+        let products = parse_receipt_text(text).unwrap();
+        let aggregated = aggregate_products(products.clone(), &SkimNameMatcher::default(), &AggregateOptions::default());
+        stats(&aggregated, &products)
+    };
+
+    let odd = summarize("Milch 1,19\nKaffee 8,99\nMilch 1,19\nBrot 2,49\nButter 1,99");
+    assert_eq!(odd.unique_products, 4);
+    assert_eq!(odd.line_items, 5);
+    assert_eq!(odd.median_price, Some(1.99));
+    assert!((odd.mean_price.unwrap() - 3.17).abs() < 1e-9);
+    assert_eq!(odd.most_expensive, Some(("kaffee".to_string(), 8.99)));
+
+    let even = summarize("Milch 1,00\nBrot 2,00\nKaffee 8,00\nButter 3,00");
+    assert_eq!(even.median_price, Some(2.5));
+
+    let empty = summarize("");
+    assert_eq!(
+        empty,
+        Stats {
+            unique_products: 0,
+            line_items: 0,
+            mean_price: None,
+            median_price: None,
+            most_expensive: None,
+            detected_currencies: BTreeMap::new(),
+            assumed_currency: 0,
+        }
+    );
+}
+
+#[test]
+fn stats_count_detected_and_assumed_currencies() {
+    let products = parse_receipt_text("Brot 1,19 EUR\nMilk $2.50\nTea 1.20 USD\nMilch 1,19").unwrap();
+    let aggregated = aggregate_products(products.clone(), &SkimNameMatcher::default(), &AggregateOptions::default());
+    let stats = stats(&aggregated, &products);
+    assert_eq!(stats.detected_currencies, BTreeMap::from([("EUR", 1), ("USD", 2)]));
+    assert_eq!(stats.assumed_currency, 1);
+}
+
+#[test]
+fn printed_total_is_read_from_the_total_line() {
+    let lines: Vec<OcrLine> = ["Milch 1,19", "Brot 2,49", "SUMME EUR 3,68", "Total 99,99"]
+        .iter()
+        .map(|text| OcrLine { text: text.to_string(), confidence: 100.0 })
+        .collect();
+    let receipt = parse_receipt(&lines, &ParseOptions::default()).unwrap();
+    assert_eq!(receipt.items.len(), 2);
+    // The first total wins
+    assert_eq!(receipt.printed_total, Some(3.68));
+    assert!((receipt.item_total() - 3.68).abs() < 1e-9);
+}
+
+#[test]
+fn payment_method_belongs_to_the_receipt() {
+    let payment = |text: &str| parse_receipt_from_text(text, &ParseOptions::default()).unwrap().payment;
+    assert_eq!(payment("Milch 1,19\nSumme 1,19\nZahlung: Karte\nBar 0,00"), Some(PaymentMethod::Card));
+    assert_eq!(payment("Milch 1,19\nBar 20,00"), Some(PaymentMethod::Cash));
+    // A product named like a payment word is not a payment line
+    assert_eq!(payment("Schoko Bar 1,99"), None);
+}
+
+fn merged_names(matcher: &dyn NameMatcher, names: &[&str], threshold: i64) -> Vec<String> {
+    let text: Vec<String> = names.iter().map(|name| format!("{} 1,00", name)).collect();
+    let products = parse_receipt_text(&text.join("\n")).unwrap();
+    let options = AggregateOptions { threshold, ..AggregateOptions::default() };
+    let mut names: Vec<String> = aggregate_products(products, matcher, &options)
+        .into_iter()
+        .map(|p| p.name)
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn skim_scores_are_normalized_by_name_length() {
+    let skim = SkimNameMatcher::default();
+    assert_eq!(merged_names(&skim, &["milch", "mlch"], 80), ["milch"]);
+    assert_eq!(merged_names(&skim, &["bio vollmilch 3 5", "bio vollmilch"], 80), ["bio vollmilch 3 5"]);
+}
+
+#[test]
+fn tokenset_merges_reordered_words() {
+    assert_eq!(merged_names(&TokenSetNameMatcher, &["coca cola", "cola coca"], 80), ["coca cola"]);
+    assert_eq!(merged_names(&TokenSetNameMatcher, &["milch", "milka"], 80), ["milch", "milka"]);
+    // The skim default still compares the words in order
+    assert_eq!(merged_names(&SkimNameMatcher::default(), &["coca cola", "cola coca"], 80), ["coca cola", "cola coca"]);
+}
+
+#[test]
+fn fuzzy_threshold_is_configurable() {
+    // Jaro-Winkler rates these at 81
+    assert_eq!(merged_names(&JaroNameMatcher, &["milch", "milka"], 80), ["milch"]);
+    assert_eq!(merged_names(&JaroNameMatcher, &["milch", "milka"], 85), ["milch", "milka"]);
+}
+
+#[test]
+fn whole_euro_shorthand_prices() {
+    assert_eq!(parse("Bier 3.-"), vec![("bier".to_string(), 3.0, 1)]);
+    assert_eq!(parse("Wurst 2,–"), vec![("wurst".to_string(), 2.0, 1)]);
+    assert_eq!(parse("Milch 1,19"), vec![("milch".to_string(), 1.19, 1)]);
+    assert_eq!(parse_european_price("3,-"), Ok(3.0));
+}
+
+#[test]
+fn thousands_separators_in_prices() {
+    assert_eq!(parse_european_price("1.299,00"), Ok(1299.0));
+    assert_eq!(parse_european_price("1,234.56"), Ok(1234.56));
+    assert_eq!(parse_european_price("2.50"), Ok(2.50));
+    assert_eq!(parse_european_price("2,50"), Ok(2.50));
+    assert_eq!(parse_european_price("1.299"), Ok(1299.0));
+    assert_eq!(parse_european_price("1.299,-"), Ok(1299.0));
+
+    let options = ParseOptions { max_price: 5000.0, ..ParseOptions::default() };
+    let parse_expensive = |text: &str| -> Vec<(String, f64)> {
+        let products = parse_receipt_text_with_options(text, &options).unwrap();
+        products.into_iter().map(|p| (p.name, p.price)).collect()
+    };
+    assert_eq!(parse_expensive("Laptop 1.299,00"), [("laptop".to_string(), 1299.0)]);
+    assert_eq!(parse_expensive("Laptop 1.299,00 EUR"), [("laptop".to_string(), 1299.0)]);
+    assert_eq!(parse_expensive("Monitor 1,234.56"), [("monitor".to_string(), 1234.56)]);
+    assert_eq!(parse_expensive("2x Laptop 1.299,00 2.598,00"), [("laptop".to_string(), 2598.0)]);
+    assert_eq!(
+        parse_expensive("Laptop 1.299,00 Maus 19,99"),
+        [("laptop".to_string(), 1299.0), ("maus".to_string(), 19.99)]
+    );
+}
+
+fn store_of(text: &str) -> Option<String> {
+    let lines: Vec<OcrLine> = text
+        .lines()
+        .map(|line| OcrLine { text: line.to_string(), confidence: 100.0 })
+        .collect();
+    parse_receipt(&lines, &ParseOptions::default()).unwrap().store
+}
+
+#[test]
+fn known_chains_are_recognized_in_the_header() {
+    assert_eq!(store_of("*** rewe Markt GmbH ***\nHauptstr. 1\nMilch 1,19").as_deref(), Some("REWE"));
+    assert_eq!(store_of("\nFiliale 0815\nALDI SÜD\nBrot 2,49").as_deref(), Some("ALDI"));
+    // Only the header counts
+    assert_eq!(store_of("Baeckerei Huber\nMilch 1,19\nLIDL Plus Coupon").as_deref(), Some("Baeckerei Huber"));
+}
+
+#[test]
+fn products_carry_the_store() {
+    let products = parse_receipt_text("~ Cafe Central ~\nKaffee 3,50").unwrap();
+    assert_eq!(products[0].store.as_deref(), Some("Cafe Central"));
+    assert_eq!(store_of("Milch 1,19"), None);
+}
+
+#[test]
+fn german_quantity_and_total_pattern() {
+    assert_eq!(
+        parse("4x Löwenbräu Original a 3,00 12,00"),
+        vec![("löwenbräu original".to_string(), 12.0, 4)]
+    );
+}
+
+#[test]
+fn euro_symbol_caps_pattern() {
+    assert_eq!(parse("1 CHICKEN MEALS €9.99"), vec![("chicken meals".to_string(), 9.99, 1)]);
+    assert_eq!(parse("2° PIZZA €25.98"), vec![("pizza".to_string(), 25.98, 2)]);
+}
+
+#[test]
+fn euro_simple_pattern() {
+    assert_eq!(parse("EXTRA SPYCIES €0.50"), vec![("extra spycies".to_string(), 0.5, 1)]);
+}
+
+#[test]
+fn german_simple_pattern_with_asterisk() {
+    assert_eq!(parse("1 Cheeseburger* 1,19"), vec![("cheeseburger".to_string(), 1.19, 1)]);
+}
+
+#[test]
+fn fallback_pattern() {
+    assert_eq!(parse("Vollmilch 1,19"), vec![("vollmilch".to_string(), 1.19, 1)]);
+}
+
+#[test]
+fn totals_and_tax_lines_are_not_products() {
+    assert_eq!(
+        parse("Milch 1,19\nSumme 12,00\nMwSt 19% 0,19\nBrot 2,49"),
+        vec![("milch".to_string(), 1.19, 1), ("brot".to_string(), 2.49, 1)]
+    );
+}
+
+#[test]
+fn skipped_lines_say_why() {
+    let skip = SkipMatcher::default();
+    let category = |line: &str| skip.matches(line).map(|reason| reason.category());
+    assert_eq!(category("Summe 12,00"), Some("total"));
+    assert_eq!(category("Wechselgeld 0,50"), Some("total"));
+    assert_eq!(category("MwSt A 1,90"), Some("tax"));
+    assert_eq!(category("19,00 % 1,90"), Some("tax"));
+    assert_eq!(category("Card ****1234"), Some("payment"));
+    assert_eq!(category("Tel: 030 123456"), Some("header"));
+    assert_eq!(category("##########"), Some("separator"));
+    assert_eq!(category("4006381333931"), Some("numeric-only"));
+    assert_eq!(category("Milch 1,19"), None);
+    assert_eq!(skip.matches("Summe 12,00").unwrap().rule(), "keyword \"summe\"");
+
+    let store = SkipMatcher { patterns: vec![regex::Regex::new("^TA-Nr").unwrap()], ..SkipMatcher::default() };
+    assert_eq!(store.matches("TA-Nr 4711").map(|reason| reason.category()), Some("store-specific"));
+}
+
+#[test]
+fn skipped_lines_are_collected_with_their_reason() {
+    let options = ParseOptions { collect_skipped: true, ..ParseOptions::default() };
+    let text = "Milch 1,19\nSumme 1,19\nMwSt 19% 0,19\nZahlung: Karte\nTel: 030 123456";
+    let receipt = parse_receipt_from_text(text, &options).unwrap();
+    let skipped: Vec<(&str, &str)> =
+        receipt.skipped.iter().map(|(reason, line)| (reason.category(), line.as_str())).collect();
+    assert_eq!(
+        skipped,
+        [("total", "Summe 1,19"), ("tax", "MwSt 19% 0,19"), ("payment", "Zahlung: Karte"), ("header", "Tel: 030 123456")]
+    );
+
+    // Off by default
+    assert!(parse_receipt_from_text(text, &ParseOptions::default()).unwrap().skipped.is_empty());
+}
+
+#[test]
+fn skip_regex_drops_matching_lines() {
+    let patterns = vec![regex::Regex::new(r"^\d{1,2}:\d{2}$").unwrap(), regex::Regex::new("^TA-Nr").unwrap()];
+    let options = ParseOptions { skip: SkipMatcher { patterns, ..SkipMatcher::default() }, ..ParseOptions::default() };
+    let products = parse_receipt_text_with_options("TA-Nr 4711 1,00\nMilch 1,19\n14:35", &options).unwrap();
+    let names: Vec<&str> = products.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, ["milch"]);
+    // Without the pattern the transaction number reads as an item
+    assert_eq!(parse("TA-Nr 4711 1,00").len(), 1);
+
+    assert!(parse_skip_regex("^TA-Nr").is_ok());
+    assert!(parse_skip_regex("^TA-Nr (").unwrap_err().to_string().contains("unclosed group"));
+    // Would skip everything
+    assert!(parse_skip_regex("x*").is_err());
+}
+
+#[test]
+fn ocr_garbled_quantity_with_line_total() {
+    assert_eq!(parse("Ix Bier a 3,00 3,00"), vec![("bier".to_string(), 3.0, 1)]);
+    assert_eq!(parse("lx Cola 2,50"), vec![("cola".to_string(), 2.5, 1)]);
+}
+
+#[test]
+fn skip_words_extend_or_replace_the_defaults() {
+    let mut skip = SkipMatcher::default();
+    assert!(skip.matches("Summer Rolls 4,99").is_some());
+    assert!(skip.matches("Albany Grill 9,99").is_none());
+
+    skip.keywords.extend(parse_skip_words("# diner\n\nAlbany\n  food club \n"));
+    assert_eq!(skip.matches("Albany Grill 9,99"), Some(SkipReason::StoreSpecific("keyword \"albany\"".to_string())));
+    assert!(skip.matches("Food Club Member").is_some());
+
+    let replaced = SkipMatcher { keywords: parse_skip_words("albany"), ..SkipMatcher::default() };
+    assert!(replaced.matches("Summer Rolls 4,99").is_none());
+    let options = ParseOptions { skip: replaced, ..ParseOptions::default() };
+    let products = parse_receipt_text_with_options("Summer Rolls 4,99", &options).unwrap();
+    assert_eq!(products[0].name, "summer rolls");
+}
+
+#[test]
+fn card_and_time_only_skip_whole_words() {
+    assert_eq!(parse("Discard Tray 4,99"), vec![("discard tray".to_string(), 4.99, 1)]);
+    assert_eq!(parse("Eieruhr Timer 6,99"), vec![("eieruhr timer".to_string(), 6.99, 1)]);
+    let skip = SkipMatcher::default();
+    assert_eq!(skip.matches("VISA CARD 12,50"), Some(SkipReason::PaymentLine("word \"card\"".to_string())));
+    assert_eq!(skip.matches("Time: 12:30"), Some(SkipReason::Header("word \"time\"".to_string())));
+}
+
+#[test]
+fn quantity_at_unit_price() {
+    assert_eq!(parse("Cola 2 @ 3,00 6,00"), vec![("cola".to_string(), 6.0, 2)]);
+    assert_eq!(parse("Brezel 3 @ 1,50"), vec![("brezel".to_string(), 4.5, 3)]);
+    // Name on the line above
+    assert_eq!(parse("COKE 12OZ\n2 @ 3,00 6,00"), vec![("coke 12oz".to_string(), 6.0, 2)]);
+    // No name anywhere, the amount still counts
+    assert_eq!(parse("3 @ 1,50"), vec![(UNNAMED_ITEM.to_string(), 4.5, 3)]);
+    assert_eq!(parse("Summe 12,00\n2 @ 3,00 6,00"), vec![(UNNAMED_ITEM.to_string(), 6.0, 2)]);
+}
+
+#[test]
+fn synonyms_rename_aliases_before_aggregation() {
+    let synonyms = Synonyms::parse("# comment\napfel = äpfel, Aepfel\n\ncoca cola = Coca-Cola 0,5l, coke\n").unwrap();
+    assert_eq!(synonyms.canonical("äpfel"), Some("apfel"));
+    assert_eq!(synonyms.canonical("aepfel"), Some("apfel"));
+    assert_eq!(synonyms.canonical("cocacola 0,5l"), Some("coca cola"));
+    assert_eq!(synonyms.canonical("birne"), None);
+
+    let options = ParseOptions { synonyms, ..ParseOptions::default() };
+    let text = "Äpfel 1,99\nApfel 2,49\nCoca-Cola 0.5l 1,29\nCoca Cola 1,29";
+    let products = parse_receipt_text_with_options(text, &options).unwrap();
+    // A threshold no fuzzy score reaches: only the synonyms merge anything
+    let exact = AggregateOptions { threshold: 100, ..AggregateOptions::default() };
+    let aggregated = aggregate_products(products, &JaroNameMatcher, &exact);
+    let totals: Vec<(&str, f64)> = aggregated.iter().map(|p| (p.name.as_str(), p.total)).collect();
+    assert_eq!(totals, [("apfel", 4.48), ("coca cola", 2.58)]);
+
+    assert!(Synonyms::parse("apfel äpfel").is_err());
+    assert!(Synonyms::parse("apfel = äpfel\nbirne = äpfel").is_err());
+}
+
+#[test]
+fn categories_sum_products_by_keyword() {
+    let categories = Categories::parse("# budget\nalcohol = Bier, wein\ngroceries = milch, brot\n").unwrap();
+    assert_eq!(categories.category("Pils Bier 0,5l"), Some("alcohol"));
+    assert_eq!(categories.category("bio vollmilch"), Some("groceries"));
+    assert_eq!(categories.category("spülmittel"), None);
+
+    let products = parse_receipt_text("Bier 1,29\nRotwein 5,99\nMilch 1,19\nBrot 2,50\nSpülmittel 1,45").unwrap();
+    let totals = category_totals(&products, &categories);
+    let totals: Vec<(&str, f64, u32)> = totals
+        .iter()
+        .map(|category| (category.name.as_str(), (category.total * 100.0).round() / 100.0, category.count))
+        .collect();
+    assert_eq!(totals, [("alcohol", 7.28, 2), ("groceries", 3.69, 2), ("uncategorized", 1.45, 1)]);
+
+    assert!(Categories::parse("alcohol bier").is_err());
+    assert!(Categories::parse("alcohol = bier\ngroceries = Bier").is_err());
+}
+
+#[test]
+fn weight_priced_items_use_the_line_total() {
+    let weighed = |text: &str| -> Vec<(String, f64, Option<Weight>)> {
+        parse_receipt_text(text).unwrap().into_iter().map(|p| (p.name, p.price, p.weight)).collect()
+    };
+    let weight = |kg, price_per_kg| Some(Weight { kg, price_per_kg });
+    assert_eq!(weighed("Tomaten 0,384 kg x 2,99 EUR/kg 1,15"), [("tomaten".to_string(), 1.15, weight(0.384, 2.99))]);
+    assert_eq!(weighed("Bananen 1,2kg x 1,49/kg 1,79 B"), [("bananen".to_string(), 1.79, weight(1.2, 1.49))]);
+    assert_eq!(weighed("Gouda 0,215 kg x 12,90 €/kg 2,77"), [("gouda".to_string(), 2.77, weight(0.215, 12.9))]);
+    // Name on the line above
+    assert_eq!(
+        weighed("Tomaten Rispe\n0,384 kg x 2,99 EUR/kg 1,15"),
+        [("tomaten rispe".to_string(), 1.15, weight(0.384, 2.99))]
+    );
+    assert_eq!(weighed("Milch 1,19"), [("milch".to_string(), 1.19, None)]);
+}
+
+#[test]
+fn net_and_tax_add_up_to_the_gross_total() {
+    let products = parse_receipt_text("Wein 5,99 A\nBrot 2,49 B\nBrot 1,29 B\nSekt 7,77 A").unwrap();
+    let aggregated = aggregate_products(products, &SkimNameMatcher::default(), &AggregateOptions::default());
+    assert_eq!(aggregated.len(), 3);
+    for (product, class) in aggregated.iter().zip(['A', 'A', 'B']) {
+        let net = product.net_total.unwrap();
+        let tax = net * f64::from(vat_rate(class).unwrap()) / 100.0;
+        assert!((net + tax - product.total).abs() < 0.01, "{}: {} + {} != {}", product.name, net, tax, product.total);
+    }
+
+    // Unknown VAT class, no net total
+    let products = parse_receipt_text("Wein 5,99 A\nWein 5,99").unwrap();
+    let aggregated = aggregate_products(products, &SkimNameMatcher::default(), &AggregateOptions::default());
+    assert_eq!(aggregated[0].net_total, None);
+}
+
+#[test]
+fn currency_next_to_the_amount_is_recorded() {
+    let currencies = |text: &str| -> Vec<(String, f64, Option<&'static str>)> {
+        parse_receipt_text(text).unwrap().into_iter().map(|p| (p.name, p.price, p.currency)).collect()
+    };
+    assert_eq!(currencies("Brot 1,19 EUR"), [("brot".to_string(), 1.19, Some("EUR"))]);
+    assert_eq!(currencies("Milk 2.50 USD"), [("milk".to_string(), 2.5, Some("USD"))]);
+    assert_eq!(currencies("Tea 1.20 £"), [("tea".to_string(), 1.2, Some("GBP"))]);
+    assert_eq!(currencies("Milk $2.50"), [("milk".to_string(), 2.5, Some("USD"))]);
+    assert_eq!(currencies("Milch 1,19"), [("milch".to_string(), 1.19, None)]);
+
+    let products = parse_receipt_text("Brot 1,19 EUR A").unwrap();
+    assert_eq!(products[0].vat_class, Some('A'));
+}
+
+#[test]
+fn the_same_product_in_two_currencies_stays_apart() {
+    let products = parse_receipt_text("Coffee 2,50 EUR\nCoffee 2.80 USD\nCoffee 2,50 EUR").unwrap();
+    let aggregated = aggregate_products(products, &SkimNameMatcher::default(), &AggregateOptions::default());
+    let totals: Vec<(&str, &str, f64)> = aggregated.iter().map(|p| (p.name.as_str(), p.currency, p.total)).collect();
+    assert_eq!(totals, [("coffee", "EUR", 5.0), ("coffee", "USD", 2.8)]);
+}
+
+#[test]
+fn currency_words_around_the_amount_are_stripped() {
+    let currencies = |text: &str| -> Vec<(String, f64, Option<&'static str>)> {
+        parse_receipt_text(text).unwrap().into_iter().map(|p| (p.name, p.price, p.currency)).collect()
+    };
+    assert_eq!(currencies("Kaffee 3,50 EUR"), [("kaffee".to_string(), 3.5, Some("EUR"))]);
+    assert_eq!(currencies("Kaffee EUR 3,50"), [("kaffee".to_string(), 3.5, Some("EUR"))]);
+    assert_eq!(currencies("EUR 3,50 Kaffee"), [("kaffee".to_string(), 3.5, Some("EUR"))]);
+}
+
+fn date_of(text: &str) -> Option<String> {
+    let lines: Vec<OcrLine> = text
+        .lines()
+        .map(|line| OcrLine { text: line.to_string(), confidence: 100.0 })
+        .collect();
+    parse_receipt(&lines, &ParseOptions::default()).unwrap().date.map(|date| date.to_string())
+}
+
+#[test]
+fn receipt_date_formats() {
+    assert_eq!(date_of("Milch 1,19\n03.04.2024 14:35").as_deref(), Some("2024-04-03"));
+    assert_eq!(date_of("Milch 1,19\n03/04/24").as_deref(), Some("2024-04-03"));
+    assert_eq!(date_of("Milch 1,19\n05.01.24").as_deref(), Some("2024-01-05"));
+    assert_eq!(date_of("2024-04-03 10:00\nMilch 1,19").as_deref(), Some("2024-04-03"));
+    assert_eq!(date_of("REWE\nMilch 1,19\nDatum: 03.04.2024").as_deref(), Some("2024-04-03"));
+    // Month first when the day can't come first
+    assert_eq!(date_of("03/25/2024").as_deref(), Some("2024-03-25"));
+    // Not a date
+    assert_eq!(date_of("Milch 1,19\n31.02.2024"), None);
+}
+
+#[test]
+fn date_next_to_the_label_wins() {
+    let text = "Coupon gueltig bis 30.06.2024\nMilch 1,19\nSumme 1,19\nDatum: 03.04.2024\nBon 4711";
+    assert_eq!(date_of(text).as_deref(), Some("2024-04-03"));
+    assert_eq!(date_of("Datum\n03.04.2024\nMHD 30.06.2024").as_deref(), Some("2024-04-03"));
+}
+
+#[test]
+fn tax_lines_are_summarized_not_listed() {
+    let receipt = parse_receipt_from_text("Brot 2,49\nMwSt 19% 1,90", &ParseOptions::default()).unwrap();
+    assert_eq!(receipt.tax_breakdown, [(19.0, 1.90)]);
+    assert_eq!(receipt.items.len(), 1);
+
+    let receipt = parse_receipt_from_text("Milch 1,19\n7% USt 0,35", &ParseOptions::default()).unwrap();
+    assert_eq!(receipt.tax_breakdown, [(7.0, 0.35)]);
+    assert!(receipt.items.iter().all(|product| product.name == "milch"));
+}
+
+#[test]
+fn preserved_case_still_merges_case_insensitively() {
+    let options = ParseOptions { preserve_case: true, ..ParseOptions::default() };
+    let text = "REWE\nLöwenbräu  Original. 3,00\nLÖWENBRÄU ORIGINAL 3,00";
+    let products = parse_receipt_text_with_options(text, &options).unwrap();
+    let names: Vec<&str> = products.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, ["Löwenbräu Original", "LÖWENBRÄU ORIGINAL"]);
+
+    let aggregated = aggregate_products(products, &JaroNameMatcher, &AggregateOptions::default());
+    let totals: Vec<(&str, f64)> = aggregated.iter().map(|p| (p.name.as_str(), p.total)).collect();
+    assert_eq!(totals, [("Löwenbräu Original", 6.0)]);
+}
+
+#[test]
+fn second_scans_are_recognized_as_duplicates() {
+    let receipt = |text: &str| parse_receipt_from_text(text, &ParseOptions::default()).unwrap();
+    let original = receipt("REWE Markt\nMilch 1,19\nBrot 2,49\nButter 1,99\nSumme 5,67\n12.03.2024 10:15 Bon 4711");
+    // One misread character in the second scan
+    let rescan = receipt("REWE Markt\nMilch 1,19\nBrot 2,49\nButter 1,99\nSumme 5,67\n12.03.2024 10:15 Bon 4717");
+    let other = receipt("ALDI\nMilch 1,19\nBrot 2,49\nSumme 3,68\n14.03.2024 18:02 Bon 815");
+    assert!(rescan.is_duplicate_of(&original));
+    assert!(!other.is_duplicate_of(&original));
+    assert!(!receipt("").is_duplicate_of(&receipt("")));
+}
+
+#[test]
+fn price_before_the_name() {
+    assert_eq!(parse("4,99 Butter"), vec![("butter".to_string(), 4.99, 1)]);
+    assert_eq!(parse("2,49 € Bio Vollmilch"), vec![("bio vollmilch".to_string(), 2.49, 1)]);
+    // Name-first lines keep their patterns
+    assert_eq!(parse("Butter 4,99"), vec![("butter".to_string(), 4.99, 1)]);
+    assert_eq!(parse("2 Cola 3,00"), vec![("cola".to_string(), 6.0, 2)]);
+    // Not a name
+    assert_eq!(parse("4,99 X1-2345"), vec![]);
+}
+
+#[test]
+fn locale_keywords_add_up() {
+    let text = "Baguette 1,20\nSous-total 1,20\nTVA 0,06\nAceitunas olivas 2,10\nIVA 0,44\nGracias 0,00";
+    let names = |skip: SkipMatcher| -> Vec<String> {
+        let options = ParseOptions { skip, ..ParseOptions::default() };
+        parse_receipt_text_with_options(text, &options).unwrap().into_iter().map(|p| p.name).collect()
+    };
+
+    let mut skip = SkipMatcher::default();
+    skip.add_locale(Locale::from_language("fra").unwrap());
+    skip.add_locale(Locale::from_language("spa").unwrap());
+    // "IVA" only as a word, so the olives stay
+    assert_eq!(names(skip), ["baguette", "aceitunas olivas"]);
+
+    let mut french = SkipMatcher::default();
+    french.add_locale(Locale::French);
+    assert!(names(french).contains(&"gracias".to_string()));
+}
+
+#[test]
+fn name_length_bounds_are_configurable() {
+    let long_name = "Extra lange importierte Spezialitaetenpackung";
+    assert_eq!(long_name.len(), 45);
+    let text = format!("{} 4,99", long_name);
+    let names = |options: &ParseOptions, text: &str| -> Vec<String> {
+        parse_receipt_text_with_options(text, options).unwrap().into_iter().map(|p| p.name).collect()
+    };
+
+    // By default only the end of the name fits
+    assert_ne!(names(&ParseOptions::default(), &text), ["extra lange importierte spezialitaetenpackung"]);
+    let long = ParseOptions { max_name_len: 60, ..ParseOptions::default() };
+    assert_eq!(names(&long, &text), ["extra lange importierte spezialitaetenpackung"]);
+
+    assert!(names(&ParseOptions::default(), "Ei 0,25").is_empty());
+    let short = ParseOptions { min_name_len: 2, ..ParseOptions::default() };
+    assert_eq!(names(&short, "Ei 0,25"), ["ei"]);
+}
+
+#[test]
+fn products_keep_the_line_they_were_read_from() {
+    let products = parse_receipt_text("REWE\nBrot 1,99 Milch\t0,99\nKaffee;2;3,50;7,00").unwrap();
+    let lines: Vec<&str> = products.iter().map(|p| p.line.as_str()).collect();
+    assert_eq!(lines, ["Brot 1,99", "Milch 0,99", "Kaffee;2;3,50;7,00"]);
+}
+
+#[test]
+fn separated_lines_are_read_by_position() {
+    assert_eq!(parse("Kaffee;2;3,50;7,00"), vec![("kaffee".to_string(), 7.0, 2)]);
+    assert_eq!(parse("Kaffee | 2 | 7,00"), vec![("kaffee".to_string(), 7.0, 2)]);
+    assert_eq!(parse("Kaffee|2x|3,50|7,00"), vec![("kaffee".to_string(), 7.0, 2)]);
+}
+
+#[test]
+fn bundled_lines_split_into_every_item() {
+    assert_eq!(
+        parse("Brot 1,99 Milch 0,99 Butter 2,29"),
+        vec![("brot".to_string(), 1.99, 1), ("milch".to_string(), 0.99, 1), ("butter".to_string(), 2.29, 1)]
+    );
+    // A number in the name is not a price, so the line stays one item
+    assert_eq!(parse("Vittel 1,50 l 0,89"), vec![("vittel 1,5 l".to_string(), 0.89, 1)]);
+    assert_eq!(parse("Wasser 0,5 l 0,49"), vec![("wasser 0,5 l".to_string(), 0.49, 1)]);
+    assert_eq!(parse("Cola 1,00 l 1,29"), vec![("cola 1 l".to_string(), 1.29, 1)]);
+}
+
+#[test]
+fn tabs_and_space_runs_separate_like_single_spaces() {
+    assert_eq!(parse("Milch\t1,19"), vec![("milch".to_string(), 1.19, 1)]);
+
+    let products = parse_receipt_text("Bio   Milch 1,19\nBio\tMilch\t\t1,19\nBio Milch 1,19").unwrap();
+    let exact = AggregateOptions { threshold: 100, ..AggregateOptions::default() };
+    let aggregated = aggregate_products(products, &JaroNameMatcher, &exact);
+    let totals: Vec<(&str, f64, u32)> = aggregated.iter().map(|p| (p.name.as_str(), p.total, p.count)).collect();
+    assert_eq!(totals, [("bio milch", 3.57, 3)]);
+
+    // The extra spaces don't count against the name length
+    let short_names = ParseOptions { max_name_len: 9, ..ParseOptions::default() };
+    let products = parse_receipt_text_with_options("Bio   Milch 1,19", &short_names).unwrap();
+    assert_eq!(products[0].name, "bio milch");
+}
+
+#[test]
+fn receipt_heading_names_store_date_and_file() {
+    let options = ParseOptions::default();
+    let mut receipt = parse_receipt_from_text("REWE Markt\nDatum: 12.03.2024\nMilch 1,19", &options).unwrap();
+    assert_eq!(receipt.heading(), "REWE, 2024-03-12");
+    receipt.source = "receipts/march/a.jpg".into();
+    assert_eq!(receipt.heading(), "REWE, 2024-03-12 (a.jpg)");
+
+    let mut receipt = parse_receipt_from_text("Milch 1,19", &options).unwrap();
+    receipt.source = "scan.pdf#page=2".into();
+    assert_eq!(receipt.heading(), "Unknown store, no date (scan.pdf#page=2)");
+}
+
+#[test]
+fn later_rule_files_override_earlier_ones() {
+    let mut synonyms = Synonyms::parse("apfel = äpfel\ncola = coke, pepsi").unwrap();
+    synonyms.merge(Synonyms::parse("pepsi = Pepsi\nbirne = birnen").unwrap());
+    assert_eq!(synonyms.canonical("äpfel"), Some("apfel"));
+    assert_eq!(synonyms.canonical("coke"), Some("cola"));
+    assert_eq!(synonyms.canonical("pepsi"), Some("pepsi"));
+    assert_eq!(synonyms.canonical("birnen"), Some("birne"));
+
+    let mut categories = Categories::parse("groceries = milch, brot\ndrinks = saft").unwrap();
+    categories.merge(Categories::parse("alcohol = bier\nbreakfast = milch").unwrap());
+    assert_eq!(categories.category("vollmilch"), Some("breakfast"));
+    assert_eq!(categories.category("brot"), Some("groceries"));
+    assert_eq!(categories.category("saft"), Some("drinks"));
+    // The later file's rules are checked first, so its keywords win over overlapping earlier ones
+    assert_eq!(categories.category("bier saft"), Some("alcohol"));
+}
+
+#[test]
+fn rounding_modes_round_to_cents() {
+    assert_eq!(round_price(0.125, Rounding::HalfUp), 0.13);
+    assert_eq!(round_price(-0.125, Rounding::HalfUp), -0.13);
+    assert_eq!(round_price(0.125, Rounding::HalfEven), 0.12);
+    assert_eq!(round_price(0.135, Rounding::HalfEven), 0.14);
+    assert_eq!(round_price(0.129, Rounding::Truncate), 0.12);
+    assert_eq!(round_price(-0.129, Rounding::Truncate), -0.12);
+    // Binary noise doesn't hide a tie: 2.675 is stored as 2.67499999...
+    assert_eq!(round_price(2.675, Rounding::HalfUp), 2.68);
+    assert_eq!(round_price(2.675, Rounding::HalfEven), 2.68);
+}
+
+#[test]
+fn amounts_serialize_with_two_decimals() {
+    #[derive(Serialize)]
+    struct Amounts {
+        #[serde(serialize_with = "serialize_cents")]
+        total: f64,
+        #[serde(serialize_with = "serialize_optional_cents")]
+        average: Option<f64>,
+        #[serde(serialize_with = "serialize_optional_cents")]
+        min: Option<f64>,
+        #[serde(serialize_with = "serialize_cents_map")]
+        grand_total: BTreeMap<&'static str, f64>,
+    }
+
+    let amounts = Amounts {
+        total: 12.0,
+        average: Some(3.5),
+        min: None,
+        grand_total: BTreeMap::from([("CHF", 0.1), ("EUR", 1299.0)]),
+    };
+    assert_eq!(
+        serde_json::to_string(&amounts).unwrap(),
+        r#"{"total":12.00,"average":3.50,"min":null,"grand_total":{"CHF":0.10,"EUR":1299.00}}"#
+    );
+}
+
+/// Parses the text in each file instead of running OCR
+struct TextReader;
+
+impl ReceiptReader for TextReader {
+    fn read_file(&self, path: &Path) -> Vec<anyhow::Result<Receipt>> {
+        let text = std::fs::read_to_string(path).unwrap();
+        if text.is_empty() {
+            return vec![Err(anyhow::anyhow!("Failed to process {}: empty file", path.display()))];
+        }
+        let mut receipt = parse_receipt_from_text(&text, &ParseOptions::default()).unwrap();
+        receipt.source = path.to_path_buf();
+        vec![Ok(receipt)]
+    }
+}
+
+#[test]
+fn receipts_are_streamed_in_directory_order() {
+    let dir = tempfile::Builder::new().prefix("receipt-analyzer-test").tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    for (name, text) in [("a.jpg", "Milch 1,19"), ("b.JPG", "Brot 2,50"), ("c.jpg", ""), ("d.txt", "Bier 1,29")] {
+        std::fs::write(dir.path().join(name), text).unwrap();
+    }
+    std::fs::write(dir.path().join("sub/e.jpg"), "Butter 2,29").unwrap();
+
+    let selection = FileSelection { extensions: vec!["jpg".to_string()], ..FileSelection::default() };
+    let inputs = [dir.path().to_path_buf(), dir.path().join("a.jpg")];
+    let mut totals: BTreeMap<String, f64> = BTreeMap::new();
+    let mut failures = Vec::new();
+    for result in process_receipts_iter(&inputs, &selection, &TextReader).unwrap() {
+        match result {
+            Ok(receipt) => {
+                for product in receipt.items {
+                    *totals.entry(product.name).or_default() += product.price;
+                }
+            }
+            Err(e) => failures.push(e.to_string()),
+        }
+    }
+    // a.jpg is read once although it is listed twice; sub/ needs `recursive`
+    assert_eq!(totals, BTreeMap::from([("brot".to_string(), 2.5), ("milch".to_string(), 1.19)]));
+    assert_eq!(failures.len(), 1);
+    assert!(failures[0].contains("c.jpg"), "{}", failures[0]);
+
+    let recursive = FileSelection { recursive: true, ..selection };
+    assert_eq!(find_receipt_files(&inputs[..1], &recursive).unwrap().len(), 4);
+}
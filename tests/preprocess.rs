@@ -0,0 +1,185 @@
+use image::{DynamicImage, GrayImage, Luma};
+use receipt_analyzer::{
+    adaptive_threshold, adjust, estimate_skew_angle, find_receipt_region, otsu_threshold, preprocess_image_with_options,
+    processed_image_name, sharpen, Binarization, PreprocessOptions,
+};
+use std::path::Path;
+
+fn gradient() -> GrayImage {
+    GrayImage::from_fn(256, 16, |x, _| Luma([x as u8]))
+}
+
+#[test]
+fn otsu_threshold_of_gradient_is_near_midpoint() {
+    let threshold = otsu_threshold(&gradient());
+    assert!((120..=135).contains(&threshold), "threshold {}", threshold);
+}
+
+#[test]
+fn otsu_binarization_is_black_and_white() {
+    let options = PreprocessOptions { binarization: Binarization::Otsu, ..PreprocessOptions::default() };
+    let processed = preprocess_image_with_options(DynamicImage::ImageLuma8(gradient()), &options).to_luma8();
+
+    assert!(processed.pixels().all(|p| p[0] == 0 || p[0] == 255));
+    assert_eq!(processed.get_pixel(10, 0)[0], 0);
+    assert_eq!(processed.get_pixel(250, 0)[0], 255);
+}
+
+#[test]
+fn neutral_contrast_and_gamma_leave_the_image_unchanged() {
+    assert_eq!(adjust(gradient(), 1.0, 1.0), gradient());
+
+    // Gamma above 1 brightens the midtones but keeps black and white
+    let brightened = adjust(gradient(), 1.0, 2.0);
+    assert!(brightened.get_pixel(128, 0)[0] > 128);
+    assert_eq!(brightened.get_pixel(0, 0)[0], 0);
+    assert_eq!(brightened.get_pixel(255, 0)[0], 255);
+}
+
+/// A band of vertical strokes 50 levels darker than the paper, under lighting that fades from a
+/// dark shadow on the left (60) to bright on the right (250). Returns the image and whether
+/// each pixel is ink
+fn unevenly_lit_text() -> (GrayImage, impl Fn(u32, u32) -> bool) {
+    let is_ink = |x: u32, y: u32| (24..40).contains(&y) && (x / 4).is_multiple_of(2);
+    let img = GrayImage::from_fn(256, 64, |x, y| {
+        let paper = 60 + x * 190 / 255;
+        Luma([(if is_ink(x, y) { paper - 50 } else { paper }) as u8])
+    });
+    (img, is_ink)
+}
+
+/// Share of the pixels that came out black exactly where there is ink
+fn accuracy(binarized: &GrayImage, is_ink: impl Fn(u32, u32) -> bool) -> f64 {
+    let correct = binarized.enumerate_pixels().filter(|(x, y, p)| (p[0] == 0) == is_ink(*x, *y)).count();
+    correct as f64 / (binarized.width() * binarized.height()) as f64
+}
+
+#[test]
+fn adaptive_thresholding_follows_uneven_lighting() {
+    let (img, is_ink) = unevenly_lit_text();
+
+    // One global threshold blacks out the shadowed paper and loses the strokes in the light
+    let otsu = PreprocessOptions { binarization: Binarization::Otsu, ..PreprocessOptions::default() };
+    let global = preprocess_image_with_options(DynamicImage::ImageLuma8(img.clone()), &otsu).to_luma8();
+    assert_eq!(global.get_pixel(2, 4)[0], 0);
+    assert_eq!(global.get_pixel(248, 30)[0], 255);
+    assert!(accuracy(&global, &is_ink) < 0.8);
+
+    let adaptive = adaptive_threshold(&img, 31);
+    assert!(adaptive.pixels().all(|p| p[0] == 0 || p[0] == 255));
+    assert_eq!(adaptive.get_pixel(2, 4)[0], 255);
+    assert_eq!(adaptive.get_pixel(248, 30)[0], 0);
+    assert_eq!(adaptive.get_pixel(4, 30)[0], 255);
+    assert!(accuracy(&adaptive, &is_ink) > 0.99);
+}
+
+/// Variance of the pixel values, which drops as an image gets blurrier
+fn variance(img: &GrayImage) -> f64 {
+    let values: Vec<f64> = img.pixels().map(|p| p[0] as f64).collect();
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64
+}
+
+#[test]
+fn sharpening_restores_contrast_of_blurred_strokes() {
+    // Vertical strokes four pixels wide, out of focus
+    let strokes = GrayImage::from_fn(64, 16, |x, _| Luma([if (x / 4) % 2 == 0 { 40 } else { 220 }]));
+    let blurred = image::imageops::blur(&strokes, 1.5);
+
+    assert_eq!(sharpen(blurred.clone(), 0.0), blurred);
+    assert!(variance(&sharpen(blurred.clone(), 1.0)) > variance(&blurred));
+}
+
+/// Dashed dark bands like lines of text, sloping down to the right by `degrees`
+fn tilted_text(degrees: f32) -> GrayImage {
+    let slope = degrees.to_radians().tan();
+    let mut img = GrayImage::from_pixel(600, 500, Luma([255]));
+    for line in 0..8 {
+        let top = 40.0 + line as f32 * 40.0;
+        for x in 20..580 {
+            // Gaps between words and between letters
+            if (x / 15) % 4 == 3 || x % 5 == 0 {
+                continue;
+            }
+            let y = (top + x as f32 * slope).round() as u32;
+            for dy in 0..8 {
+                img.put_pixel(x, y + dy, Luma([0]));
+            }
+        }
+    }
+    img
+}
+
+#[test]
+fn skew_of_tilted_text_is_estimated() {
+    let angle = estimate_skew_angle(&tilted_text(7.0));
+    assert!((angle - 7.0).abs() <= 1.0, "angle {}", angle);
+    let angle = estimate_skew_angle(&tilted_text(-4.0));
+    assert!((angle + 4.0).abs() <= 1.0, "angle {}", angle);
+}
+
+#[test]
+fn deskew_straightens_tilted_text() {
+    let options = PreprocessOptions {
+        deskew: true,
+        binarization: Binarization::None,
+        ..PreprocessOptions::default()
+    };
+    let processed = preprocess_image_with_options(DynamicImage::ImageLuma8(tilted_text(7.0)), &options).to_luma8();
+    let angle = estimate_skew_angle(&processed);
+    assert!(angle.abs() <= 1.0, "angle {}", angle);
+}
+
+/// A white receipt with a few lines of text lying on a dark, slightly grainy desk, next to a pen
+fn receipt_on_desk() -> GrayImage {
+    GrayImage::from_fn(600, 800, |x, y| {
+        let on_receipt = (150..450).contains(&x) && (100..700).contains(&y);
+        let text = on_receipt && (180..420).contains(&x) && (y - 100) % 40 < 12 && (y - 100) % 40 > 2;
+        let pen = (40..60).contains(&x) && (300..500).contains(&y);
+        Luma([match () {
+            _ if text || pen => 20,
+            _ if on_receipt => 240,
+            _ => 60 + ((x * 7 + y * 13) % 11) as u8,
+        }])
+    })
+}
+
+#[test]
+fn autocrop_keeps_only_the_receipt() {
+    let region = find_receipt_region(&receipt_on_desk()).expect("receipt outline");
+    assert!((150..165).contains(&region.x) && (100..115).contains(&region.y), "{:?}", region);
+    assert!((435..=450).contains(&(region.x + region.width)), "{:?}", region);
+    assert!((685..=700).contains(&(region.y + region.height)), "{:?}", region);
+
+    let options = PreprocessOptions { autocrop: true, ..PreprocessOptions::default() };
+    let cropped = preprocess_image_with_options(DynamicImage::ImageLuma8(receipt_on_desk()), &options).to_luma8();
+    assert_eq!((cropped.width(), cropped.height()), (region.width, region.height));
+}
+
+#[test]
+fn autocrop_leaves_images_without_a_clear_outline_whole() {
+    // A scan of the receipt alone, and a receipt running off the bottom of the photo
+    let scan = GrayImage::from_fn(300, 600, |x, y| Luma([if x > 30 && x < 270 && y % 40 < 10 { 20 } else { 240 }]));
+    let cut_off =
+        GrayImage::from_fn(600, 800, |x, y| Luma([if (150..450).contains(&x) && y > 100 { 240 } else { 60 }]));
+    assert_eq!(find_receipt_region(&scan), None);
+    assert_eq!(find_receipt_region(&cut_off), None);
+    assert_eq!(find_receipt_region(&gradient()), None);
+
+    let options = PreprocessOptions { autocrop: true, ..PreprocessOptions::default() };
+    let processed = preprocess_image_with_options(DynamicImage::ImageLuma8(cut_off), &options);
+    assert_eq!((processed.width(), processed.height()), (600, 800));
+}
+
+#[test]
+fn processed_image_names_keep_the_stem_and_differ_per_source() {
+    let name = processed_image_name(Path::new("a/receipt.jpg"));
+    assert!(name.starts_with("receipt-") && name.ends_with(".png"), "{}", name);
+    assert_eq!(name, processed_image_name(Path::new("a/receipt.jpg")));
+    assert_ne!(name, processed_image_name(Path::new("b/receipt.jpg")));
+
+    let page = processed_image_name(Path::new("scan.pdf#page=2"));
+    assert!(page.starts_with("scan-page2-") && page.ends_with(".png"), "{}", page);
+    let entry = processed_image_name(Path::new("backup.zip#2024/IMG_02.jpg"));
+    assert!(entry.starts_with("IMG_02-") && entry.ends_with(".png"), "{}", entry);
+}